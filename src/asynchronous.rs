@@ -0,0 +1,1116 @@
+//! A non-blocking API on tokio, enabled with the `async` feature.
+//!
+//! `AsyncLight` keeps the same cached fields as `Light`, with the same getters.
+//! The connect and read timeouts apply, while retries, the rate limit, music mode and the background light
+//! are only available on the blocking API.
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::TryRecvError;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures_core::Stream;
+use serde_json::Value;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::sync::{mpsc, Notify, oneshot};
+use tokio::task::JoinHandle;
+use tokio::time::{self, Instant};
+
+use crate::{any_local_addr, DEFAULT_LOCAL_PORT, interface_of, MULTICAST_ADDR, MULTICAST_PORT, parse_search_response, YeeClient};
+use crate::conn::{Changes, next_free_id};
+use crate::discovery::{DiscoveryReport, is_silent, RejectedResponse, SearchOptions, subnet_hosts};
+use crate::err::{io_or_timeout, YeeError};
+use crate::interface::Interface;
+use crate::fields::{ActiveMode, ColorMode, PowerMode, PowerStatus, Rgb};
+use crate::flow::Flow;
+use crate::light::{adjusted_props, Channel, decode_props, decode_sleep_timer, expect_ok, Light};
+use crate::notify::{parse_notification, PropertyChange};
+use crate::prop::{PropValue, Property};
+use crate::req::{AdjustAction, AdjustProp, Command, Req, Response, Transition};
+use crate::scene::Scene;
+
+// each response is sent along with its position in the stream, see `conn::Changes`
+type Pending = Arc<Mutex<Option<HashMap<u16, oneshot::Sender<(u64, Response)>>>>>;
+type Subscribers = Arc<Mutex<Vec<mpsc::UnboundedSender<PropertyChange>>>>;
+
+/// Discovers lights like `YeeClient`, without blocking the thread.
+#[derive(Debug, Clone)]
+pub struct AsyncYeeClient {
+    seekers: Vec<AsyncSeeker>,
+    multicast_addr: SocketAddr,
+    search_msg: String,
+}
+
+// shared with the tasks running the searches
+#[derive(Debug, Clone)]
+struct AsyncSeeker {
+    socket: Arc<UdpSocket>,
+    interface: Option<Ipv4Addr>,
+    netmask: Option<Ipv4Addr>,
+}
+
+impl AsyncYeeClient {
+    /// Must be called from within a tokio runtime.
+    pub fn new() -> Result<AsyncYeeClient, YeeError> {
+        let addr = SocketAddrV4::new(MULTICAST_ADDR, MULTICAST_PORT);
+        Self::with_addr(addr, DEFAULT_LOCAL_PORT)
+    }
+
+    /// Must be called from within a tokio runtime.
+    pub fn with_addr(multicast_addr: SocketAddrV4, local_port: u16) -> Result<AsyncYeeClient, YeeError> {
+        Self::from_client(YeeClient::with_addr(multicast_addr, local_port)?)
+    }
+
+    /// See `YeeClient::on_interfaces`, must be called from within a tokio runtime.
+    pub fn on_interfaces(interfaces: &[Interface]) -> Result<AsyncYeeClient, YeeError> {
+        Self::from_client(YeeClient::on_interfaces(interfaces)?)
+    }
+
+    /// See `YeeClient::with_interfaces`, must be called from within a tokio runtime.
+    pub fn with_interfaces(multicast_addr: SocketAddrV4, local_port: u16, interfaces: &[Interface])
+                           -> Result<AsyncYeeClient, YeeError> {
+        Self::from_client(YeeClient::with_interfaces(multicast_addr, local_port, interfaces)?)
+    }
+
+    /// See `YeeClient::new_v6`, must be called from within a tokio runtime.
+    pub fn new_v6(interface: u32) -> Result<AsyncYeeClient, YeeError> {
+        Self::from_client(YeeClient::new_v6(interface)?)
+    }
+
+    /// See `YeeClient::with_addr_v6`, must be called from within a tokio runtime.
+    pub fn with_addr_v6(multicast_addr: SocketAddrV6, local_port: u16) -> Result<AsyncYeeClient, YeeError> {
+        Self::from_client(YeeClient::with_addr_v6(multicast_addr, local_port)?)
+    }
+
+    pub(crate) fn from_client(client: YeeClient) -> Result<AsyncYeeClient, YeeError> {
+        let seekers = client.seekers.into_iter()
+            .map(|seeker| {
+                let socket = Arc::new(UdpSocket::from_std(seeker.socket)?);
+                Ok(AsyncSeeker { socket, interface: seeker.interface, netmask: seeker.netmask })
+            })
+            .collect::<io::Result<_>>()?;
+        Ok(AsyncYeeClient { seekers, multicast_addr: client.multicast_addr, search_msg: client.search_msg })
+    }
+
+    pub async fn get_response(&self, timeout: Duration) -> Vec<AsyncLight> {
+        self.get_report(timeout).await.lights
+    }
+
+    /// See `YeeClient::get_report`.
+    pub async fn get_report(&self, timeout: Duration) -> DiscoveryReport<AsyncLight> {
+        self.search(&SearchOptions::new(timeout)).await
+    }
+
+    /// See `YeeClient::search`.
+    pub async fn search(&self, options: &SearchOptions) -> DiscoveryReport<AsyncLight> {
+        self.discover(options).into_report().await
+    }
+
+    /// See `YeeClient::discover`, the search runs on its own task until it ends or the stream is dropped.
+    pub fn discover(&self, options: &SearchOptions) -> AsyncDiscovery {
+        let (lights, receiver) = mpsc::unbounded_channel();
+        let (client, options) = (self.clone(), options.clone());
+        let task = tokio::spawn(async move { client.run_search(&options, lights).await });
+        AsyncDiscovery { receiver, task: Some(task) }
+    }
+
+    async fn run_search(&self, options: &SearchOptions, lights: mpsc::UnboundedSender<AsyncLight>)
+                        -> (Vec<RejectedResponse>, Vec<YeeError>) {
+        let mut rejected = Vec::new();
+        let mut send_errors = Vec::new();
+
+        // each interface is read by its own task until the deadline, or until the search ends early
+        let deadline = Instant::now() + options.timeout();
+        let (events, mut received) = mpsc::unbounded_channel();
+        let mut tasks = AbortOnDrop(self.seekers.iter().map(|seeker| {
+            let (socket, interface, events) = (seeker.socket.clone(), seeker.interface, events.clone());
+            tokio::spawn(async move {
+                let mut buf = [0u8; 1024];
+                while let Ok(received) = time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+                    if let Ok((size, sender)) = received {
+                        if events.send(SearchEvent::Response(buf[..size].to_vec(), sender, interface)).is_err() {
+                            break;
+                        }
+                    }
+                }
+            })
+        }).collect());
+
+        // the ids of the lights found or being connected to
+        let mut seen = HashSet::new();
+        let mut found = HashSet::new();
+        let mut probes = 0;
+        let mut next_probe = Instant::now();
+        while !lights.is_closed() {
+            if probes < options.probe_count() && Instant::now() >= next_probe {
+                self.send_probe(&mut send_errors).await;
+                probes += 1;
+                next_probe = Instant::now() + options.next_interval();
+            }
+            let wake = if probes < options.probe_count() { next_probe.min(deadline) } else { deadline };
+            let event = match time::timeout_at(wake, received.recv()).await {
+                Ok(Some(event)) => event,
+                Ok(None) => break,
+                Err(_) if Instant::now() >= deadline => break,
+                Err(_) => continue
+            };
+            match event {
+                SearchEvent::Response(buf, sender, interface) => {
+                    let mut new_light = match parse_search_response(&buf, &sender) {
+                        Ok(new_light) => new_light,
+                        Err(error) => {
+                            rejected.push(RejectedResponse { sender, error });
+                            continue;
+                        }
+                    };
+                    if !seen.insert(new_light.id().to_string()) {
+                        continue;
+                    }
+                    let subnets = self.seekers.iter().map(|s| (s.interface, s.netmask));
+                    new_light.interface = interface_of(subnets, interface, &sender);
+                    // connecting can take up to the connect timeout, which must not hold up the deadline
+                    let events = events.clone();
+                    tasks.0.push(tokio::spawn(async move {
+                        let id = new_light.id().to_string();
+                        let _ = events.send(SearchEvent::Connected(sender, id, AsyncLight::from_light(new_light).await.map(Box::new)));
+                    }));
+                }
+                SearchEvent::Connected(_, id, Ok(new_light)) => {
+                    found.insert(id);
+                    if lights.send(*new_light).is_err() {
+                        break;
+                    }
+                }
+                SearchEvent::Connected(sender, id, Err(error)) => {
+                    // a later response may get through
+                    seen.remove(&id);
+                    rejected.push(RejectedResponse { sender, error });
+                }
+            }
+            if options.is_satisfied(found.iter().map(String::as_str)) {
+                break;
+            }
+        }
+        (rejected, send_errors)
+    }
+
+    /// See `YeeClient::probe`.
+    pub async fn probe(&self, addr: IpAddr, timeout: Duration) -> Result<AsyncLight, YeeError> {
+        self.probe_at(SocketAddr::new(addr, MULTICAST_PORT), timeout).await
+    }
+
+    /// See `YeeClient::sweep`, the hosts are probed by `concurrency` tasks.
+    pub async fn sweep(&self, cidr: &str, timeout: Duration, concurrency: usize)
+                       -> Result<DiscoveryReport<AsyncLight>, YeeError> {
+        self.sweep_port(cidr, MULTICAST_PORT, timeout, concurrency).await
+    }
+
+    async fn sweep_port(&self, cidr: &str, port: u16, timeout: Duration, concurrency: usize)
+                        -> Result<DiscoveryReport<AsyncLight>, YeeError> {
+        let hosts = subnet_hosts(cidr)?;
+        let workers = concurrency.clamp(1, hosts.len());
+        let hosts = Arc::new(Mutex::new(hosts.into_iter()));
+        let mut probers = AbortOnDrop((0..workers).map(|_| {
+            let (client, hosts) = (self.clone(), hosts.clone());
+            tokio::spawn(async move {
+                let mut results = Vec::new();
+                loop {
+                    let host = match hosts.lock().unwrap().next() {
+                        Some(host) => host,
+                        None => break
+                    };
+                    let target = SocketAddr::new(host.into(), port);
+                    // only a host that doesn't answer is left out, a light that can't be connected to is reported
+                    let result = match client.search_at(target, timeout).await {
+                        Ok(light) => AsyncLight::from_light(light).await,
+                        Err(error) if is_silent(&error) => continue,
+                        Err(error) => Err(error)
+                    };
+                    results.push((target, result));
+                }
+                results
+            })
+        }).collect());
+
+        let mut report = DiscoveryReport::default();
+        for prober in probers.0.iter_mut() {
+            for (target, result) in prober.await.unwrap_or_default() {
+                match result {
+                    Ok(light) => report.lights.push(light),
+                    Err(error) => report.rejected.push(RejectedResponse { sender: target, error })
+                }
+            }
+        }
+        report.lights.sort_by_key(|light| *light.location());
+        Ok(report)
+    }
+
+    async fn probe_at(&self, target: SocketAddr, timeout: Duration) -> Result<AsyncLight, YeeError> {
+        AsyncLight::from_light(self.search_at(target, timeout).await?).await
+    }
+
+    /// See `unicast_search`.
+    async fn search_at(&self, target: SocketAddr, timeout: Duration) -> Result<Light, YeeError> {
+        // connected, so only the response of the target is received
+        let socket = UdpSocket::bind(any_local_addr(&target)).await?;
+        socket.connect(target).await?;
+        socket.send(self.search_msg.as_bytes()).await?;
+
+        let mut buf = [0u8; 1024];
+        let size = time::timeout(timeout, socket.recv(&mut buf)).await
+            .map_err(|_| YeeError::Timeout { operation: "probe" })??;
+        parse_search_response(&buf[..size], &target)
+    }
+
+    async fn send_probe(&self, errors: &mut Vec<YeeError>) {
+        for seeker in &self.seekers {
+            if let Err(e) = seeker.socket.send_to(self.search_msg.as_bytes(), self.multicast_addr).await {
+                errors.push(e.into());
+            }
+        }
+    }
+}
+
+// what the loop of a search waits for
+enum SearchEvent {
+    // a datagram received on the given interface
+    Response(Vec<u8>, SocketAddr, Option<Ipv4Addr>),
+    // the outcome of connecting to the light with the given id
+    Connected(SocketAddr, String, Result<Box<AsyncLight>, YeeError>),
+}
+
+/// A search in progress, from `AsyncYeeClient::discover`, as a `Stream` of the lights as soon as they're connected to.
+#[derive(Debug)]
+pub struct AsyncDiscovery {
+    receiver: mpsc::UnboundedReceiver<AsyncLight>,
+    task: Option<JoinHandle<(Vec<RejectedResponse>, Vec<YeeError>)>>,
+}
+
+impl AsyncDiscovery {
+    /// The next light found, or `None` once the search has ended.
+    pub async fn next(&mut self) -> Option<AsyncLight> {
+        self.receiver.recv().await
+    }
+
+    /// Runs the rest of the search, the report has the lights that weren't yielded yet.
+    pub async fn into_report(mut self) -> DiscoveryReport<AsyncLight> {
+        let mut report = DiscoveryReport::default();
+        while let Some(light) = self.receiver.recv().await {
+            report.lights.push(light);
+        }
+        if let Some(task) = self.task.take() {
+            if let Ok((rejected, send_errors)) = task.await {
+                report.rejected = rejected;
+                report.send_errors = send_errors;
+            }
+        }
+        report
+    }
+}
+
+impl Stream for AsyncDiscovery {
+    type Item = AsyncLight;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<AsyncLight>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+impl Drop for AsyncDiscovery {
+    fn drop(&mut self) {
+        if let Some(task) = self.task.take() {
+            task.abort();
+        }
+    }
+}
+
+// stops the tasks of a search once it ends or is dropped, e.g. the readers of the sockets the next search reads from
+struct AbortOnDrop<T>(Vec<JoinHandle<T>>);
+
+impl<T> Drop for AbortOnDrop<T> {
+    fn drop(&mut self) {
+        self.0.iter().for_each(JoinHandle::abort);
+    }
+}
+
+/// A light controlled without blocking the thread, from `AsyncYeeClient` or `AsyncLight::from_light`.
+#[derive(Debug)]
+pub struct AsyncLight {
+    light: Light,
+    conn: AsyncConnection,
+}
+
+impl AsyncLight {
+    /// Opens a new connection to the light, which must be done from within a tokio runtime.
+    ///
+    /// The connect and read timeouts of the light apply, like on the blocking API.
+    pub async fn from_light(mut light: Light) -> Result<AsyncLight, YeeError> {
+        // the blocking connection is never used again
+        light.conn = None;
+        let connect = TcpStream::connect(*light.location());
+        let stream = match light.connect_timeout() {
+            Some(timeout) => time::timeout(timeout, connect).await
+                .map_err(|_| YeeError::Timeout { operation: "connect" })?,
+            None => connect.await
+        }.map_err(io_or_timeout("connect"))?;
+        Ok(AsyncLight { light, conn: AsyncConnection::new(stream) })
+    }
+
+    pub async fn set_ct_abx(&mut self, temperature: u16, transition: Transition) -> Result<(), YeeError> {
+        let cmd = self.light.set_ct_abx_cmd(Channel::Main, temperature, transition)?;
+        self.run(cmd).await
+    }
+
+    pub async fn set_rgb(&mut self, rgb: Rgb, transition: Transition) -> Result<(), YeeError> {
+        let cmd = self.light.set_rgb_cmd(Channel::Main, rgb, transition)?;
+        self.run(cmd).await
+    }
+
+    pub async fn set_bright(&mut self, brightness: u8, transition: Transition) -> Result<(), YeeError> {
+        let cmd = self.light.set_bright_cmd(Channel::Main, brightness, transition)?;
+        self.run(cmd).await
+    }
+
+    pub async fn set_hsv(&mut self, hue: u16, sat: u8, transition: Transition) -> Result<(), YeeError> {
+        let cmd = self.light.set_hsv_cmd(Channel::Main, hue, sat, transition)?;
+        self.run(cmd).await
+    }
+
+    pub async fn set_power(&mut self, power: PowerStatus, transition: Transition) -> Result<(), YeeError> {
+        let cmd = self.light.set_power_cmd(Channel::Main, power, transition, None)?;
+        self.run(cmd).await
+    }
+
+    /// See `Light::set_power_with_mode`.
+    pub async fn set_power_with_mode(&mut self, power: PowerStatus, transition: Transition, mode: PowerMode)
+                                     -> Result<(), YeeError> {
+        let cmd = self.light.set_power_cmd(Channel::Main, power, transition, Some(mode))?;
+        self.run(cmd).await
+    }
+
+    /// See `Light::set_active_mode`.
+    pub async fn set_active_mode(&mut self, mode: ActiveMode, transition: Transition) -> Result<(), YeeError> {
+        let cmd = self.light.set_active_mode_cmd(mode, transition)?;
+        self.run(cmd).await
+    }
+
+    pub async fn toggle(&mut self) -> Result<(), YeeError> {
+        let cmd = self.light.toggle_cmd(Channel::Main)?;
+        self.run(cmd).await
+    }
+
+    /// Toggles the main and background lights at the same time.
+    pub async fn toggle_both(&mut self) -> Result<(), YeeError> {
+        let cmd = self.light.toggle_both_cmd()?;
+        self.run(cmd).await
+    }
+
+    pub async fn start_cf(&mut self, flow: &Flow) -> Result<(), YeeError> {
+        let cmd = self.light.start_cf_cmd(Channel::Main, flow)?;
+        self.run(cmd).await
+    }
+
+    pub async fn stop_cf(&mut self) -> Result<(), YeeError> {
+        let cmd = self.light.stop_cf_cmd(Channel::Main)?;
+        self.run(cmd).await
+    }
+
+    pub async fn set_scene(&mut self, scene: &Scene) -> Result<(), YeeError> {
+        let cmd = self.light.set_scene_cmd(Channel::Main, scene)?;
+        self.run(cmd).await
+    }
+
+    /// See `Light::adjust`.
+    pub async fn adjust(&mut self, action: AdjustAction, prop: AdjustProp) -> Result<(), YeeError> {
+        let cmd = self.light.adjust_cmd(Channel::Main, action, prop)?;
+        self.run(cmd).await?;
+        self.resync(adjusted_props(prop)).await;
+        Ok(())
+    }
+
+    pub async fn adjust_bright(&mut self, percentage: i8, duration: Duration) -> Result<(), YeeError> {
+        let cmd = self.light.adjust_by_cmd(Channel::Main, AdjustProp::Bright, percentage, duration)?;
+        self.run(cmd).await?;
+        self.resync(adjusted_props(AdjustProp::Bright)).await;
+        Ok(())
+    }
+
+    pub async fn adjust_ct(&mut self, percentage: i8, duration: Duration) -> Result<(), YeeError> {
+        let cmd = self.light.adjust_by_cmd(Channel::Main, AdjustProp::Ct, percentage, duration)?;
+        self.run(cmd).await?;
+        self.resync(adjusted_props(AdjustProp::Ct)).await;
+        Ok(())
+    }
+
+    pub async fn adjust_color(&mut self, percentage: i8, duration: Duration) -> Result<(), YeeError> {
+        let cmd = self.light.adjust_by_cmd(Channel::Main, AdjustProp::Color, percentage, duration)?;
+        self.run(cmd).await?;
+        self.resync(adjusted_props(AdjustProp::Color)).await;
+        Ok(())
+    }
+
+    /// See `Light::set_name`.
+    pub async fn set_name(&mut self, name: &str) -> Result<(), YeeError> {
+        let cmd = self.light.set_name_cmd(name)?;
+        self.run(cmd).await
+    }
+
+    pub async fn save_as_default(&mut self) -> Result<(), YeeError> {
+        let cmd = self.light.set_default_cmd()?;
+        self.run(cmd).await
+    }
+
+    /// See `Light::set_sleep_timer`.
+    pub async fn set_sleep_timer(&mut self, delay: Duration) -> Result<(), YeeError> {
+        let req = self.light.set_sleep_timer_req(delay)?;
+        self.send_req(req).await?;
+        Ok(())
+    }
+
+    /// See `Light::sleep_timer`.
+    pub async fn sleep_timer(&mut self) -> Result<Option<Duration>, YeeError> {
+        let req = self.light.sleep_timer_req()?;
+        let result = self.send_req(req).await?;
+        decode_sleep_timer(result)
+    }
+
+    pub async fn cancel_sleep_timer(&mut self) -> Result<(), YeeError> {
+        let req = self.light.cancel_sleep_timer_req()?;
+        self.send_req(req).await?;
+        Ok(())
+    }
+
+    /// See `Light::get_prop`.
+    pub async fn get_prop(&mut self, props: &[Property]) -> Result<HashMap<Property, PropValue>, YeeError> {
+        let req = self.light.get_prop_req(props)?;
+        let values = self.send_req(req).await?;
+        decode_props(props, values)
+    }
+
+    /// Re-reads every cached field from the light.
+    pub async fn refresh(&mut self) -> Result<(), YeeError> {
+        let props = self.light.refresh_props();
+        let values = self.get_prop(&props).await?;
+        self.light.apply_read(&props, values);
+        Ok(())
+    }
+
+    /// See `Light::call`.
+    pub async fn call(&mut self, method: &str, params: Vec<Value>) -> Result<Vec<Value>, YeeError> {
+        let req = Req::new(method.to_string(), params);
+        self.send_req(req).await
+    }
+
+    /// See `Light::call_all`.
+    pub async fn call_all(&mut self, reqs: Vec<Req>) -> Vec<Result<Vec<Value>, YeeError>> {
+        let mut pending = Vec::with_capacity(reqs.len());
+        for req in reqs {
+            pending.push(self.conn.send(req).await);
+        }
+        // the requests are pipelined, so they share the timeout
+        let end = self.light.read_timeout().map(|t| Instant::now() + t);
+        let mut results = Vec::with_capacity(pending.len());
+        for p in pending {
+            results.push(match p {
+                Ok(p) => p.wait(end.map(|end| end.saturating_duration_since(Instant::now()))).await,
+                Err(e) => Err(e)
+            });
+        }
+        self.apply_notifications();
+        results
+    }
+
+    /// Returns a channel that receives every change the light reports on its own, as soon as it arrives.
+    ///
+    /// The changes are applied to the cached fields on the next call on this light or in `poll_notifications`.
+    pub fn subscribe(&mut self) -> mpsc::UnboundedReceiver<PropertyChange> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.conn.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    /// Waits up to `timeout` for notifications and applies them, returning how many property changes were received.
+    pub async fn poll_notifications(&mut self, timeout: Duration) -> Result<usize, YeeError> {
+        let deadline = Instant::now() + timeout;
+        let mut count = 0;
+        loop {
+            match self.conn.changes.try_recv() {
+                Ok(change) => {
+                    self.light.apply(change.property, change.value);
+                    count += 1;
+                }
+                Err(TryRecvError::Disconnected) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+                Err(TryRecvError::Empty) => {
+                    if time::timeout_at(deadline, self.conn.arrived.notified()).await.is_err() {
+                        return Ok(count);
+                    }
+                }
+            }
+        }
+    }
+
+    async fn resync(&mut self, props: &[Property]) {
+        if !self.light.support().contains("get_prop") {
+            return self.light.mark_stale(props);
+        }
+        match self.get_prop(props).await {
+            Ok(values) => self.light.apply_read(props, values),
+            Err(_) => self.light.mark_stale(props)
+        }
+    }
+
+    async fn run(&mut self, cmd: Command) -> Result<(), YeeError> {
+        let result = self.send_req(cmd.req).await?;
+        expect_ok(result)?;
+        self.light.apply_all(cmd.changes);
+        Ok(())
+    }
+
+    async fn send_req(&mut self, req: Req) -> Result<Vec<Value>, YeeError> {
+        let result = match self.conn.send(req).await {
+            Ok(pending) => pending.receive(self.light.read_timeout()).await,
+            Err(e) => Err(e)
+        };
+        match result {
+            Ok((seq, res)) => {
+                self.apply_notifications_before(seq);
+                res.into_result()
+            }
+            Err(e) => {
+                self.apply_notifications();
+                Err(e)
+            }
+        }
+    }
+
+    fn apply_notifications(&mut self) {
+        let changes = self.conn.changes.take_all();
+        self.light.apply_all(changes.into_iter().map(|c| (c.property, c.value)));
+    }
+
+    /// See `Light::apply_notifications_before`.
+    fn apply_notifications_before(&mut self, seq: u64) {
+        let changes = self.conn.changes.take_before(seq);
+        self.light.apply_all(changes.into_iter().map(|c| (c.property, c.value)));
+    }
+}
+
+// the cached fields of the light, the blocking settings like the rate limit don't apply here
+impl AsyncLight {
+    pub fn location(&self) -> &SocketAddr {
+        self.light.location()
+    }
+
+    /// See `Light::interface`.
+    pub fn interface(&self) -> Option<Ipv4Addr> {
+        self.light.interface()
+    }
+
+    pub fn id(&self) -> &str {
+        self.light.id()
+    }
+
+    pub fn model(&self) -> &str {
+        self.light.model()
+    }
+
+    pub fn fw_ver(&self) -> u8 {
+        self.light.fw_ver()
+    }
+
+    pub fn support(&self) -> &HashSet<String> {
+        self.light.support()
+    }
+
+    pub fn power(&self) -> &PowerStatus {
+        self.light.power()
+    }
+
+    pub fn bright(&self) -> u8 {
+        self.light.bright()
+    }
+
+    pub fn color_mode(&self) -> &ColorMode {
+        self.light.color_mode()
+    }
+
+    pub fn ct(&self) -> u16 {
+        self.light.ct()
+    }
+
+    pub fn rgb(&self) -> &Rgb {
+        self.light.rgb()
+    }
+
+    pub fn hue(&self) -> u16 {
+        self.light.hue()
+    }
+
+    pub fn sat(&self) -> u8 {
+        self.light.sat()
+    }
+
+    pub fn name(&self) -> &str {
+        self.light.name()
+    }
+
+    /// See `Light::active_mode`.
+    pub fn active_mode(&self) -> Option<ActiveMode> {
+        self.light.active_mode()
+    }
+
+    /// See `Light::nl_br`.
+    pub fn nl_br(&self) -> Option<u8> {
+        self.light.nl_br()
+    }
+
+    /// See `Light::stale`.
+    pub fn stale(&self) -> &HashSet<Property> {
+        self.light.stale()
+    }
+
+    /// The timeout `from_light` connected with, set on the `Light` beforehand.
+    pub fn connect_timeout(&self) -> Option<Duration> {
+        self.light.connect_timeout()
+    }
+
+    pub fn read_timeout(&self) -> Option<Duration> {
+        self.light.read_timeout()
+    }
+
+    /// Sets how long to wait for the response to each request, or `None` to wait forever.
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) -> Result<(), YeeError> {
+        self.light.set_read_timeout(timeout)
+    }
+}
+
+/// The async counterpart of `conn::Connection`, with the reader running as a tokio task.
+#[derive(Debug)]
+struct AsyncConnection {
+    writer: OwnedWriteHalf,
+    // None once the reader has stopped
+    pending: Pending,
+    next_id: u16,
+    changes: Arc<Changes>,
+    // woken for each change, and once the reader has stopped
+    arrived: Arc<Notify>,
+    subscribers: Subscribers,
+    reader: JoinHandle<()>,
+}
+
+impl AsyncConnection {
+    fn new(stream: TcpStream) -> AsyncConnection {
+        let (reader, writer) = stream.into_split();
+        let pending: Pending = Arc::new(Mutex::new(Some(HashMap::new())));
+        let subscribers: Subscribers = Arc::new(Mutex::new(Vec::new()));
+        let changes = Arc::new(Changes::default());
+        let arrived = Arc::new(Notify::new());
+
+        let reader = tokio::spawn(read_loop(reader, pending.clone(), changes.clone(), arrived.clone(),
+                                            subscribers.clone()));
+
+        AsyncConnection { writer, pending, next_id: 0, changes, arrived, subscribers, reader }
+    }
+
+    /// Sends the request with a new id, unique among the requests still waiting for a response.
+    async fn send(&mut self, mut req: Req) -> Result<AsyncPendingResponse, YeeError> {
+        let (sender, receiver) = oneshot::channel();
+        {
+            let mut pending = self.pending.lock().unwrap();
+            let pending = pending.as_mut()
+                .ok_or_else(|| YeeError::from(io::Error::from(io::ErrorKind::NotConnected)))?;
+            self.next_id = next_free_id(self.next_id, pending);
+            req.id = self.next_id;
+            pending.insert(req.id, sender);
+        }
+
+        let mut json = serde_json::to_string(&req).unwrap();
+        json.push_str("\r\n");
+        if let Err(e) = self.writer.write_all(json.as_bytes()).await {
+            if let Some(pending) = self.pending.lock().unwrap().as_mut() {
+                pending.remove(&req.id);
+            }
+            return Err(e.into());
+        }
+        Ok(AsyncPendingResponse { id: req.id, receiver, pending: self.pending.clone() })
+    }
+}
+
+/// The async counterpart of `conn::PendingResponse`.
+#[derive(Debug)]
+struct AsyncPendingResponse {
+    id: u16,
+    receiver: oneshot::Receiver<(u64, Response)>,
+    pending: Pending,
+}
+
+impl AsyncPendingResponse {
+    /// Waits for the response, for at most `timeout` if there is one.
+    async fn wait(self, timeout: Option<Duration>) -> Result<Vec<Value>, YeeError> {
+        self.receive(timeout).await.and_then(|(_, res)| res.into_result())
+    }
+
+    /// Like `wait`, also returning the position of the response in the stream, for `Changes::take_before`.
+    async fn receive(self, timeout: Option<Duration>) -> Result<(u64, Response), YeeError> {
+        let res = match timeout {
+            Some(timeout) => match time::timeout(timeout, self.receiver).await {
+                Ok(res) => res,
+                Err(_) => {
+                    // a late response is dropped by the reader
+                    if let Some(pending) = self.pending.lock().unwrap().as_mut() {
+                        pending.remove(&self.id);
+                    }
+                    return Err(YeeError::Timeout { operation: "receive a response" });
+                }
+            },
+            None => self.receiver.await
+        };
+        match res {
+            Ok(res) => Ok(res),
+            // the reader stopped before the response arrived
+            Err(_) => Err(io::Error::from(io::ErrorKind::UnexpectedEof).into())
+        }
+    }
+}
+
+impl Drop for AsyncConnection {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+async fn read_loop(reader: OwnedReadHalf, pending: Pending, changes: Arc<Changes>, arrived: Arc<Notify>,
+                   subscribers: Subscribers) {
+    let mut lines = BufReader::new(reader).lines();
+    let mut seq: u64 = 0;
+    while let Ok(Some(line)) = lines.next_line().await {
+        seq += 1;
+        if let Ok(res) = serde_json::from_str::<Response>(&line) {
+            let sender = pending.lock().unwrap().as_mut().and_then(|p| p.remove(&res.id));
+            if let Some(sender) = sender {
+                let _ = sender.send((seq, res));
+            }
+        } else if let Some(new_changes) = parse_notification(&line) {
+            let mut subscribers = subscribers.lock().unwrap();
+            for change in new_changes {
+                // drop subscribers that hung up
+                subscribers.retain(|s| s.send(change.clone()).is_ok());
+                changes.push(seq, change);
+            }
+            arrived.notify_one();
+        }
+    }
+    // fails every request still waiting
+    pending.lock().unwrap().take();
+    changes.close();
+    arrived.notify_one();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, TcpListener};
+
+    use serde_json::json;
+
+    use crate::fields::ColorMode;
+    use crate::light::tests::{fake_light, get_map};
+
+    use super::*;
+
+    async fn async_light(support: &'static str, replies: Vec<&'static str>)
+                         -> anyhow::Result<(AsyncLight, std::thread::JoinHandle<Vec<Value>>)> {
+        let (addr, handle) = fake_light(replies)?;
+        let location = format!("yeelight://{}", addr);
+        let mut map = get_map();
+        map.insert("support", support);
+        map.insert("Location", &location);
+        let light = AsyncLight::from_light(Light::from_fields(&map)?).await?;
+        Ok((light, handle))
+    }
+
+    #[tokio::test]
+    async fn sends_commands_and_updates_fields() -> anyhow::Result<()> {
+        // given
+        let (mut light, handle) = async_light(
+            "set_bright toggle get_prop",
+            vec![r#""result":["ok"]"#,
+                 r#""result":["ok"]"#,
+                 r#""result":["on","80","1","4000","16711680","120","55","desk","",""]"#]).await?;
+
+        // when
+        light.set_bright(75, Transition::sudden()).await?;
+        let bright = light.bright();
+        light.toggle().await?;
+        let power = *light.power();
+        light.refresh().await?;
+
+        // then
+        let received = handle.join().unwrap();
+        assert_eq!(received[0]["params"], json!([75, "sudden", 0]));
+        assert_eq!(received[1]["method"], "toggle");
+        assert_eq!(bright, 75);
+        assert_eq!(power, PowerStatus::Off);
+        assert_eq!(light.power(), &PowerStatus::On);
+        assert_eq!(light.color_mode(), &ColorMode::Color);
+        assert_eq!(light.name(), "desk");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn notifications_update_fields() -> anyhow::Result<()> {
+        // given
+        let (mut light, _handle) = async_light(
+            "set_bright",
+            vec![r#"{"id":{id},"result":["ok"]}
+{"method":"props","params":{"ct":4200}}"#,
+                 // keeps the connection open
+                 r#""result":["ok"]"#]).await?;
+        let mut changes = light.subscribe();
+
+        // when
+        light.set_bright(50, Transition::sudden()).await?;
+        light.poll_notifications(Duration::from_millis(200)).await?;
+
+        // then
+        assert_eq!(light.ct(), 4200);
+        assert_eq!(changes.try_recv()?, PropertyChange { property: Property::Ct, value: PropValue::Ct(4200) });
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn later_notification_not_overwritten() -> anyhow::Result<()> {
+        // given
+        let (mut light, _handle) = async_light(
+            "set_bright",
+            vec![r#"{"id":{id},"result":["ok"]}
+{"method":"props","params":{"bright":80}}"#,
+                 // keeps the connection open
+                 r#""result":["ok"]"#]).await?;
+
+        // when
+        light.set_bright(50, Transition::sudden()).await?;
+        let bright = light.bright();
+        let count = light.poll_notifications(Duration::from_millis(200)).await?;
+
+        // then
+        assert_eq!(bright, 50);
+        assert_eq!(count, 1);
+        assert_eq!(light.bright(), 80);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn device_error_returned() -> anyhow::Result<()> {
+        // given
+        let (mut light, _handle) = async_light(
+            "set_bright", vec![r#""error":{"code":-5000,"message":"general error"}"#]).await?;
+
+        // when
+        let result = light.set_bright(12, Transition::sudden()).await;
+
+        // then
+        assert!(matches!(result, Err(YeeError::Device { code: -5000, .. })));
+        assert_eq!(light.bright(), 34);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn response_times_out() -> anyhow::Result<()> {
+        // given
+        let listener = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))?;
+        let location = format!("yeelight://{}", listener.local_addr()?);
+        // never answers, reading until the light is dropped
+        let handle = std::thread::spawn(move || -> std::io::Result<usize> {
+            let (mut stream, _) = listener.accept()?;
+            std::io::Read::read_to_end(&mut stream, &mut Vec::new())
+        });
+        let mut map = get_map();
+        map.insert("support", "set_bright");
+        map.insert("Location", &location);
+        let mut light = Light::from_fields(&map)?;
+        light.set_read_timeout(Some(Duration::from_millis(100)))?;
+        let mut light = AsyncLight::from_light(light).await?;
+
+        // when
+        let start = Instant::now();
+        let result = light.set_bright(50, Transition::sudden()).await;
+
+        // then
+        assert!(matches!(result, Err(YeeError::Timeout { .. })));
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert!(light.conn.pending.lock().unwrap().as_ref().is_some_and(HashMap::is_empty));
+        drop(light);
+        handle.join().unwrap()?;
+        Ok(())
+    }
+
+    // a client searching a fake group on localhost, along with the listener of the group
+    fn local_client() -> anyhow::Result<(AsyncYeeClient, std::net::UdpSocket)> {
+        let multicast_listener = std::net::UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))?;
+        let seeker = std::net::UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))?;
+        seeker.set_nonblocking(true)?;
+        let multicast_addr = multicast_listener.local_addr()?;
+        let seeker = AsyncSeeker { socket: Arc::new(UdpSocket::from_std(seeker)?), interface: None, netmask: None };
+        let search_msg = crate::SEARCH_MSG.to_string();
+        Ok((AsyncYeeClient { seekers: vec![seeker], multicast_addr, search_msg }, multicast_listener))
+    }
+
+    fn search_response(location: SocketAddr) -> String {
+        format!("HTTP/1.1 200 OK\r
+Location: yeelight://{}\r
+id: 0x12345abcde\r
+model: color\r
+fw_ver: 20\r
+support: get_prop set_power\r
+power: on\r
+bright: 40\r
+color_mode: 2\r
+ct: 3300\r
+rgb: 2\r
+hue: 4\r
+sat: 100\r
+name: light_one\r\n", location)
+    }
+
+    #[tokio::test]
+    async fn discovers_lights() -> anyhow::Result<()> {
+        // given
+        let (client, multicast_listener) = local_client()?;
+        let client_addr = client.seekers[0].socket.local_addr()?;
+
+        let fake_listener = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))?;
+        let msg = search_response(fake_listener.local_addr()?);
+        let fake_sender = std::net::UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))?;
+        fake_sender.send_to(msg.as_bytes(), client_addr)?;
+        fake_sender.send_to(msg.as_bytes(), client_addr)?;
+
+        // when
+        let lights = client.get_response(Duration::from_millis(300)).await;
+
+        // then
+        let mut buf = [0; 512];
+        let (size, _) = multicast_listener.recv_from(&mut buf)?;
+        assert_eq!(&buf[..size], crate::SEARCH_MSG.as_bytes());
+        assert_eq!(lights.len(), 1);
+        assert_eq!(lights[0].id(), "0x12345abcde");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn streams_lights_as_they_answer() -> anyhow::Result<()> {
+        // given
+        let (client, _multicast_listener) = local_client()?;
+        let client_addr = client.seekers[0].socket.local_addr()?;
+
+        let fake_listener = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))?;
+        let fake_sender = std::net::UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))?;
+        fake_sender.send_to(search_response(fake_listener.local_addr()?).as_bytes(), client_addr)?;
+        fake_sender.send_to(b"not a light", client_addr)?;
+
+        // when
+        let start = Instant::now();
+        let mut discovery = client.discover(&SearchOptions::new(Duration::from_secs(1)));
+        let first = std::future::poll_fn(|cx| Pin::new(&mut discovery).poll_next(cx)).await;
+        let first_after = start.elapsed();
+        let report = discovery.into_report().await;
+
+        // then
+        assert_eq!(first.map(|light| light.id().to_string()), Some("0x12345abcde".to_string()));
+        assert!(first_after < Duration::from_millis(500));
+        assert!(report.lights.is_empty());
+        assert_eq!(report.rejected.len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn deadline_holds_while_connecting() -> anyhow::Result<()> {
+        // given
+        let (client, _multicast_listener) = local_client()?;
+        let client_addr = client.seekers[0].socket.local_addr()?;
+        // a listener that never accepts, once its backlog is full further connects hang
+        let full = socket2::Socket::new(socket2::Domain::IPV4, socket2::Type::STREAM, None)?;
+        full.bind(&SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0).into())?;
+        full.listen(0)?;
+        let full_addr = full.local_addr()?.as_socket().unwrap();
+        let _backlog: Vec<_> = (0..4)
+            .filter_map(|_| std::net::TcpStream::connect_timeout(&full_addr, Duration::from_millis(50)).ok())
+            .collect();
+        let fake_sender = std::net::UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))?;
+        fake_sender.send_to(search_response(full_addr).as_bytes(), client_addr)?;
+
+        // when
+        let start = Instant::now();
+        let report = client.search(&SearchOptions::new(Duration::from_millis(300))).await;
+
+        // then
+        assert!(start.elapsed() < Duration::from_secs(1));
+        // still connecting when the search ended
+        assert!(report.lights.is_empty());
+        assert!(report.rejected.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn probes_and_sweeps() -> anyhow::Result<()> {
+        // given
+        let (client, _multicast_listener) = local_client()?;
+        let fake_listener = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))?;
+        let fake_light = std::net::UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))?;
+        let fake_addr = fake_light.local_addr()?;
+        let response = search_response(fake_listener.local_addr()?);
+        std::thread::spawn(move || -> std::io::Result<()> {
+            let mut buf = [0; 512];
+            let (_, sender) = fake_light.recv_from(&mut buf)?;
+            fake_light.send_to(response.as_bytes(), sender)?;
+            Ok(())
+        });
+
+        // when
+        let light = client.probe_at(fake_addr, Duration::from_secs(2)).await?;
+        let report = client.sweep("127.0.0.0/29", Duration::from_millis(100), 4).await?;
+
+        // then
+        assert_eq!(light.id(), "0x12345abcde");
+        assert!(report.lights.is_empty());
+        assert!(report.rejected.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sweep_reports_unreachable_lights() -> anyhow::Result<()> {
+        // given
+        let (client, _multicast_listener) = local_client()?;
+        let fake_listener = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))?;
+        let closed = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))?.local_addr()?;
+        let reachable = std::net::UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 2), 0))?;
+        let unreachable = std::net::UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 3),
+                                                                      reachable.local_addr()?.port()))?;
+        let port = reachable.local_addr()?.port();
+        let unreachable_addr = unreachable.local_addr()?;
+        for (fake_light, location) in [(reachable, fake_listener.local_addr()?), (unreachable, closed)] {
+            std::thread::spawn(move || -> std::io::Result<()> {
+                let mut buf = [0; 512];
+                let (_, sender) = fake_light.recv_from(&mut buf)?;
+                fake_light.send_to(search_response(location).as_bytes(), sender)?;
+                Ok(())
+            });
+        }
+
+        // when
+        let report = client.sweep_port("127.0.0.0/29", port, Duration::from_millis(500), 4).await?;
+
+        // then
+        assert_eq!(report.lights.len(), 1);
+        assert_eq!(report.lights[0].location(), &fake_listener.local_addr()?);
+        assert_eq!(report.rejected.len(), 1);
+        assert_eq!(report.rejected[0].sender, unreachable_addr);
+        assert!(matches!(&report.rejected[0].error,
+                         YeeError::IoError { source } if source.kind() == std::io::ErrorKind::ConnectionRefused));
+        Ok(())
+    }
+}
@@ -0,0 +1,257 @@
+use std::time::Duration;
+
+use crate::err::YeeError;
+use crate::fields::{ColorMode, PowerStatus, Rgb};
+use crate::flow::Flow;
+use crate::light::{adjusted_props, BG_PROPS, Channel, Light};
+use crate::req::{AdjustAction, AdjustProp, Transition};
+use crate::scene::Scene;
+
+/// The cached state of the background light, kept on the `Light` it belongs to.
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct BackgroundState {
+    // discovery only describes the main light, so the fields are made up until read with get_prop
+    pub(crate) known: bool,
+    pub(crate) power: PowerStatus,
+    pub(crate) bright: u8,
+    pub(crate) color_mode: ColorMode,
+    pub(crate) ct: u16,
+    pub(crate) rgb: Rgb,
+    pub(crate) hue: u16,
+    pub(crate) sat: u8,
+}
+
+impl BackgroundState {
+    pub(crate) fn new() -> BackgroundState {
+        BackgroundState {
+            known: false,
+            power: PowerStatus::Off,
+            bright: 0,
+            color_mode: ColorMode::ColorTemperature,
+            ct: 0,
+            rgb: Rgb::empty(),
+            hue: 0,
+            sat: 0,
+        }
+    }
+}
+
+/// The background (ambient) light of dual-light models, from `Light::background`.
+///
+/// Commands are sent over the connection of the main light.
+#[derive(Debug)]
+pub struct BackgroundLight<'a> {
+    light: &'a mut Light,
+}
+
+impl<'a> BackgroundLight<'a> {
+    pub(crate) fn new(light: &'a mut Light) -> BackgroundLight<'a> {
+        BackgroundLight { light }
+    }
+
+    pub fn set_ct_abx(&mut self, temperature: u16, transition: Transition) -> Result<(), YeeError> {
+        let cmd = self.light.set_ct_abx_cmd(Channel::Background, temperature, transition)?;
+        self.light.run(cmd)
+    }
+
+    pub fn set_rgb(&mut self, rgb: Rgb, transition: Transition) -> Result<(), YeeError> {
+        let cmd = self.light.set_rgb_cmd(Channel::Background, rgb, transition)?;
+        self.light.run(cmd)
+    }
+
+    pub fn set_bright(&mut self, brightness: u8, transition: Transition) -> Result<(), YeeError> {
+        let cmd = self.light.set_bright_cmd(Channel::Background, brightness, transition)?;
+        self.light.run(cmd)
+    }
+
+    pub fn set_hsv(&mut self, hue: u16, sat: u8, transition: Transition) -> Result<(), YeeError> {
+        let cmd = self.light.set_hsv_cmd(Channel::Background, hue, sat, transition)?;
+        self.light.run(cmd)
+    }
+
+    pub fn set_power(&mut self, power: PowerStatus, transition: Transition) -> Result<(), YeeError> {
+        let cmd = self.light.set_power_cmd(Channel::Background, power, transition, None)?;
+        self.light.run(cmd)
+    }
+
+    pub fn toggle(&mut self) -> Result<(), YeeError> {
+        let cmd = self.light.toggle_cmd(Channel::Background)?;
+        self.light.run(cmd)
+    }
+
+    pub fn start_cf(&mut self, flow: &Flow) -> Result<(), YeeError> {
+        let cmd = self.light.start_cf_cmd(Channel::Background, flow)?;
+        self.light.run(cmd)
+    }
+
+    pub fn stop_cf(&mut self) -> Result<(), YeeError> {
+        let cmd = self.light.stop_cf_cmd(Channel::Background)?;
+        self.light.run(cmd)
+    }
+
+    pub fn set_scene(&mut self, scene: &Scene) -> Result<(), YeeError> {
+        let cmd = self.light.set_scene_cmd(Channel::Background, scene)?;
+        self.light.run(cmd)
+    }
+
+    /// See `Light::adjust`.
+    pub fn adjust(&mut self, action: AdjustAction, prop: AdjustProp) -> Result<(), YeeError> {
+        let cmd = self.light.adjust_cmd(Channel::Background, action, prop)?;
+        self.light.run(cmd)?;
+        self.resync(prop);
+        Ok(())
+    }
+
+    pub fn adjust_bright(&mut self, percentage: i8, duration: Duration) -> Result<(), YeeError> {
+        let cmd = self.light.adjust_by_cmd(Channel::Background, AdjustProp::Bright, percentage, duration)?;
+        self.light.run(cmd)?;
+        self.resync(AdjustProp::Bright);
+        Ok(())
+    }
+
+    pub fn adjust_ct(&mut self, percentage: i8, duration: Duration) -> Result<(), YeeError> {
+        let cmd = self.light.adjust_by_cmd(Channel::Background, AdjustProp::Ct, percentage, duration)?;
+        self.light.run(cmd)?;
+        self.resync(AdjustProp::Ct);
+        Ok(())
+    }
+
+    pub fn adjust_color(&mut self, percentage: i8, duration: Duration) -> Result<(), YeeError> {
+        let cmd = self.light.adjust_by_cmd(Channel::Background, AdjustProp::Color, percentage, duration)?;
+        self.light.run(cmd)?;
+        self.resync(AdjustProp::Color);
+        Ok(())
+    }
+
+    /// Re-reads the cached background fields from the light.
+    pub fn refresh(&mut self) -> Result<(), YeeError> {
+        let values = self.light.get_prop(BG_PROPS)?;
+        self.light.apply_read(BG_PROPS, values);
+        Ok(())
+    }
+
+    fn resync(&mut self, prop: AdjustProp) {
+        let props: Vec<_> = adjusted_props(prop).iter()
+            .map(|p| Channel::Background.prop(*p))
+            .collect();
+        self.light.resync(&props)
+    }
+
+    /// Whether the cached fields were read from the light, they are placeholders until then.
+    pub fn known(&self) -> bool {
+        self.light.bg.known
+    }
+
+    pub fn power(&self) -> &PowerStatus {
+        &self.light.bg.power
+    }
+
+    pub fn bright(&self) -> u8 {
+        self.light.bg.bright
+    }
+
+    pub fn color_mode(&self) -> &ColorMode {
+        &self.light.bg.color_mode
+    }
+
+    pub fn ct(&self) -> u16 {
+        self.light.bg.ct
+    }
+
+    pub fn rgb(&self) -> &Rgb {
+        &self.light.bg.rgb
+    }
+
+    pub fn hue(&self) -> u16 {
+        self.light.bg.hue
+    }
+
+    pub fn sat(&self) -> u8 {
+        self.light.bg.sat
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::light::tests::connected_light;
+
+    use super::*;
+
+    #[test]
+    fn sends_background_methods() -> anyhow::Result<()> {
+        // given
+        let (mut light, handle) = connected_light(
+            "bg_set_power bg_set_rgb bg_toggle",
+            vec![r#""result":["ok"]"#, r#""result":["ok"]"#, r#""result":["ok"]"#])?;
+
+        // when
+        let mut bg = light.background()?;
+        bg.set_power(PowerStatus::On, Transition::sudden())?;
+        bg.set_rgb(Rgb::new(0, 255, 0), Transition::sudden())?;
+        bg.toggle()?;
+
+        // then
+        assert!(!bg.known());
+        assert_eq!(bg.power(), &PowerStatus::Off);
+        assert_eq!(bg.rgb(), &Rgb::new(0, 255, 0));
+        let received = handle.join().unwrap();
+        assert_eq!(received[0]["method"], "bg_set_power");
+        assert_eq!(received[1]["method"], "bg_set_rgb");
+        assert_eq!(received[1]["params"], json!([65280, "sudden", 0]));
+        assert_eq!(received[2]["method"], "bg_toggle");
+        // main light is untouched
+        assert_eq!(light.power(), &PowerStatus::On);
+        assert_eq!(light.rgb(), &Rgb::new(10, 10, 10));
+        Ok(())
+    }
+
+    #[test]
+    fn refresh_background() -> anyhow::Result<()> {
+        // given
+        let (mut light, handle) = connected_light(
+            "get_prop bg_set_power",
+            vec![r#""result":["on","45","1","3000","255","10","20"]"#,
+                 r#""result":["off","45","1","3000","255","10","20"]"#])?;
+
+        // when
+        let mut bg = light.background()?;
+        let (known, power) = (bg.known(), *bg.power());
+        bg.refresh()?;
+
+        // then
+        assert!(known);
+        assert_eq!(power, PowerStatus::On);
+        assert_eq!(bg.power(), &PowerStatus::Off);
+        assert_eq!(bg.bright(), 45);
+        assert_eq!(bg.color_mode(), &ColorMode::Color);
+        assert_eq!(bg.ct(), 3000);
+        assert_eq!(bg.rgb(), &Rgb::new(0, 0, 255));
+        assert_eq!(bg.hue(), 10);
+        assert_eq!(bg.sat(), 20);
+        // the first handle reads the state, later ones use the cache
+        assert!(light.background()?.known());
+        let received = handle.join().unwrap();
+        assert_eq!(received.len(), 2);
+        assert_eq!(received[0]["params"],
+                   json!(["bg_power", "bg_bright", "bg_lmode", "bg_ct", "bg_rgb", "bg_hue", "bg_sat"]));
+        Ok(())
+    }
+
+    #[test]
+    fn toggle_both_and_unsupported() -> anyhow::Result<()> {
+        // given
+        let (mut light, handle) = connected_light("dev_toggle", vec![r#""result":["ok"]"#])?;
+
+        // when
+        light.toggle_both()?;
+
+        // then
+        assert!(light.background().is_err());
+        assert_eq!(light.power(), &PowerStatus::Off);
+        assert_eq!(light.bg.power, PowerStatus::On);
+        assert_eq!(handle.join().unwrap()[0]["method"], "dev_toggle");
+        Ok(())
+    }
+}
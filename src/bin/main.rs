@@ -1,25 +1,22 @@
-use std::io::{Read, Write};
-use std::net::TcpStream;
 use std::thread::sleep;
 use std::time::Duration;
 
+use yeelib_rs::discovery::SearchOptions;
 use yeelib_rs::err::YeeError;
-use yeelib_rs::fields::{Rgb, PowerStatus};
 use yeelib_rs::light::Light;
-use yeelib_rs::req::Transition;
 use yeelib_rs::YeeClient;
 
 fn main() -> Result<(), YeeError> {
     let client = YeeClient::new()?;
-    let mut res: Vec<Light> = loop {
-        let lights = client.get_response(Duration::from_secs(1));
-        // sometimes, it doesn't find anything, so rerun
-        if lights.len() == 0 {
-            println!("zero");
-        } else {
-            break lights;
-        }
-    };
+    // a single search message is sometimes lost, so send a few
+    let options = SearchOptions::new(Duration::from_secs(5))
+        .probes(5, Duration::from_millis(500))
+        .until_found(1);
+    let mut res: Vec<Light> = client.search(&options).lights;
+    if res.is_empty() {
+        println!("zero");
+        return Ok(());
+    }
     let light = res.get_mut(0).unwrap();
     println!("{:?}", light);
 
@@ -80,6 +77,4 @@ fn main() -> Result<(), YeeError> {
     //     println!("{}", String::from_utf8(buf.to_vec()).unwrap());
     //     sleep(Duration::from_secs(5));
     // }
-
-    Ok(())
 }
\ No newline at end of file
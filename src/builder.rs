@@ -0,0 +1,275 @@
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, UdpSocket};
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+#[cfg(feature = "async")]
+use crate::asynchronous::AsyncYeeClient;
+use crate::{DEFAULT_LOCAL_PORT, DEFAULT_SEARCH_TARGET, MULTICAST_ADDR, MULTICAST_PORT, search_msg, Seeker, YeeClient};
+use crate::err::YeeError;
+use crate::interface::{self, Interface};
+
+/// Configures the sockets of a `YeeClient`, from `YeeClient::builder`.
+///
+/// Defaults to what `YeeClient::new` does: the IPv4 group, `DEFAULT_LOCAL_PORT` on the interface the OS picks,
+/// and the OS defaults for the socket options.
+#[derive(Debug, Clone)]
+pub struct YeeClientBuilder {
+    multicast_addr: SocketAddr,
+    local_port: u16,
+    interfaces: Vec<Interface>,
+    reuse_addr: bool,
+    reuse_port: bool,
+    multicast_ttl: Option<u32>,
+    multicast_loop: Option<bool>,
+    search_target: String,
+}
+
+impl Default for YeeClientBuilder {
+    fn default() -> Self {
+        YeeClientBuilder {
+            multicast_addr: SocketAddrV4::new(MULTICAST_ADDR, MULTICAST_PORT).into(),
+            local_port: DEFAULT_LOCAL_PORT,
+            interfaces: Vec::new(),
+            reuse_addr: false,
+            reuse_port: false,
+            multicast_ttl: None,
+            multicast_loop: None,
+            search_target: DEFAULT_SEARCH_TARGET.to_string(),
+        }
+    }
+}
+
+impl YeeClientBuilder {
+    pub fn new() -> YeeClientBuilder {
+        Self::default()
+    }
+
+    /// The group to search, an IPv6 group is joined on the interface given by its scope id.
+    pub fn multicast_addr(mut self, multicast_addr: SocketAddr) -> Self {
+        self.multicast_addr = multicast_addr;
+        self
+    }
+
+    /// The port the responses are received on.
+    pub fn local_port(mut self, local_port: u16) -> Self {
+        self.local_port = local_port;
+        self
+    }
+
+    /// Lets the OS pick a free port, so several clients can search from the same host.
+    pub fn ephemeral_port(self) -> Self {
+        self.local_port(0)
+    }
+
+    /// Searches on each of `interfaces` instead of the one the OS picks, IPv4 only.
+    pub fn interfaces(mut self, interfaces: &[Interface]) -> Self {
+        self.interfaces = interfaces.to_vec();
+        self
+    }
+
+    /// Sets `SO_REUSEADDR`, letting the local port be bound again while it's still in use.
+    pub fn reuse_addr(mut self, reuse: bool) -> Self {
+        self.reuse_addr = reuse;
+        self
+    }
+
+    /// Sets `SO_REUSEPORT`, letting other sockets with the option bind the same local port.
+    #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+    pub fn reuse_port(mut self, reuse: bool) -> Self {
+        self.reuse_port = reuse;
+        self
+    }
+
+    /// How many hops the search message may take, the OS default is usually 1, i.e. the local network only.
+    pub fn multicast_ttl(mut self, ttl: u32) -> Self {
+        self.multicast_ttl = Some(ttl);
+        self
+    }
+
+    /// Whether the search message is also delivered to the sockets of this host.
+    pub fn multicast_loop(mut self, enabled: bool) -> Self {
+        self.multicast_loop = Some(enabled);
+        self
+    }
+
+    /// The `ST` header of the search message, `wifi_bulb` by default.
+    pub fn search_target(mut self, search_target: &str) -> Self {
+        self.search_target = search_target.to_string();
+        self
+    }
+
+    pub fn build(self) -> Result<YeeClient, YeeError> {
+        if self.search_target.is_empty() || self.search_target.contains(&['\r', '\n'][..]) {
+            return Err(YeeError::InvalidValue { field_name: "search_target", value: self.search_target });
+        }
+        let seekers = match self.multicast_addr {
+            SocketAddr::V4(multicast_addr) if self.interfaces.is_empty() => {
+                // we don't know the IPs of the lights, so listen to all traffic
+                let socket = self.bind_v4(&multicast_addr, Ipv4Addr::UNSPECIFIED, false)?;
+                vec![Seeker { socket, interface: None, netmask: None }]
+            }
+            SocketAddr::V4(multicast_addr) => {
+                let mut seekers = Vec::with_capacity(self.interfaces.len());
+                // every seeker binds the unspecified address, so they share a fixed port
+                let shared = self.interfaces.len() > 1 && self.local_port != 0;
+                for interface in &self.interfaces {
+                    let addr = interface.addr()?;
+                    let socket = self.bind_v4(&multicast_addr, addr, shared)?;
+                    seekers.push(Seeker { socket, interface: Some(addr), netmask: interface::netmask(addr) });
+                }
+                seekers
+            }
+            SocketAddr::V6(multicast_addr) if self.interfaces.is_empty() => {
+                vec![Seeker { socket: self.bind_v6(&multicast_addr)?, interface: None, netmask: None }]
+            }
+            SocketAddr::V6(_) => return Err(YeeError::InvalidValue {
+                field_name: "interfaces",
+                value: "IPv6 groups are joined on the interface given by the scope id".to_string(),
+            })
+        };
+
+        let search_msg = search_msg(&self.multicast_addr, &self.search_target);
+        Ok(YeeClient { seekers, multicast_addr: self.multicast_addr, search_msg })
+    }
+
+    /// Builds an `AsyncYeeClient`, must be called from within a tokio runtime.
+    #[cfg(feature = "async")]
+    pub fn build_async(self) -> Result<AsyncYeeClient, YeeError> {
+        AsyncYeeClient::from_client(self.build()?)
+    }
+
+    /// Binds the unspecified address either way, as a socket bound to a unicast address doesn't receive
+    /// the datagrams sent to the group. `interface` is where the group is joined and the search is sent.
+    fn bind_v4(&self, multicast_addr: &SocketAddrV4, interface: Ipv4Addr, shared: bool) -> Result<UdpSocket, YeeError> {
+        let socket = self.socket(Domain::IPV4)?;
+        if shared {
+            socket.set_reuse_address(true)?;
+            #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+            socket.set_reuse_port(true)?;
+        }
+        if let Some(ttl) = self.multicast_ttl {
+            socket.set_multicast_ttl_v4(ttl)?;
+        }
+        if let Some(enabled) = self.multicast_loop {
+            socket.set_multicast_loop_v4(enabled)?;
+        }
+        if !interface.is_unspecified() {
+            socket.set_multicast_if_v4(&interface)?;
+        }
+        socket.bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, self.local_port).into())?;
+        socket.join_multicast_v4(multicast_addr.ip(), &interface)?;
+        Ok(socket.into())
+    }
+
+    fn bind_v6(&self, multicast_addr: &SocketAddrV6) -> Result<UdpSocket, YeeError> {
+        let socket = self.socket(Domain::IPV6)?;
+        if let Some(hops) = self.multicast_ttl {
+            socket.set_multicast_hops_v6(hops)?;
+        }
+        if let Some(enabled) = self.multicast_loop {
+            socket.set_multicast_loop_v6(enabled)?;
+        }
+        socket.bind(&SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, self.local_port, 0, 0).into())?;
+        socket.join_multicast_v6(multicast_addr.ip(), multicast_addr.scope_id())?;
+        Ok(socket.into())
+    }
+
+    fn socket(&self, domain: Domain) -> Result<Socket, YeeError> {
+        let socket = Socket::new(domain, Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_reuse_address(self.reuse_addr)?;
+        #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+        socket.set_reuse_port(self.reuse_port)?;
+        socket.set_nonblocking(true)?;
+        Ok(socket)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use super::*;
+
+    #[test]
+    fn bind_ephemeral_ports() -> anyhow::Result<()> {
+        // when
+        let first = YeeClientBuilder::new().ephemeral_port().build()?;
+        let second = YeeClientBuilder::new().ephemeral_port().build()?;
+
+        // then
+        let first_port = first.seekers[0].socket.local_addr()?.port();
+        let second_port = second.seekers[0].socket.local_addr()?.port();
+        assert_ne!(first_port, 0);
+        assert_ne!(first_port, second_port);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+    fn share_port_with_reuse() -> anyhow::Result<()> {
+        // given
+        let builder = YeeClientBuilder::new().local_port(0).reuse_addr(true).reuse_port(true);
+        let first = builder.clone().build()?;
+        let port = first.seekers[0].socket.local_addr()?.port();
+
+        // when
+        let second = builder.clone().local_port(port).build();
+        let without_reuse = YeeClientBuilder::new().local_port(port).build();
+
+        // then
+        assert_eq!(second?.seekers[0].socket.local_addr()?.port(), port);
+        assert!(matches!(without_reuse, Err(YeeError::IoError { .. })));
+        Ok(())
+    }
+
+    #[test]
+    fn apply_socket_options() -> anyhow::Result<()> {
+        // when
+        let client = YeeClientBuilder::new()
+            .ephemeral_port()
+            .multicast_ttl(4)
+            .multicast_loop(false)
+            .interfaces(&[Interface::Addr(Ipv4Addr::LOCALHOST)])
+            .build()?;
+
+        // then
+        let socket = &client.seekers[0].socket;
+        assert_eq!(socket.multicast_ttl_v4()?, 4);
+        assert!(!socket.multicast_loop_v4()?);
+        assert_eq!(socket.local_addr()?.ip(), IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        assert_eq!(socket2::SockRef::from(socket).multicast_if_v4()?, Ipv4Addr::LOCALHOST);
+        Ok(())
+    }
+
+    #[test]
+    fn interfaces_share_fixed_port() -> anyhow::Result<()> {
+        // given
+        let port = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))?.local_addr()?.port();
+
+        // when
+        let client = YeeClientBuilder::new()
+            .local_port(port)
+            .interfaces(&[Interface::Addr(Ipv4Addr::LOCALHOST), Interface::Addr(Ipv4Addr::LOCALHOST)])
+            .build()?;
+
+        // then
+        assert_eq!(client.seekers.len(), 2);
+        for seeker in &client.seekers {
+            assert_eq!(seeker.socket.local_addr()?, SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port).into());
+            assert_eq!(seeker.netmask, Some(Ipv4Addr::new(255, 0, 0, 0)));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn custom_search_target() -> anyhow::Result<()> {
+        // when
+        let client = YeeClientBuilder::new().ephemeral_port().search_target("ssdp:all").build()?;
+        let invalid = YeeClientBuilder::new().ephemeral_port().search_target("wifi_bulb\r\nMX: 3").build();
+
+        // then
+        assert!(client.search_msg.ends_with("\r\nST: ssdp:all"));
+        assert!(matches!(invalid, Err(YeeError::InvalidValue { field_name: "search_target", .. })));
+        Ok(())
+    }
+}
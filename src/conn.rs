@@ -0,0 +1,375 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use serde_json::Value;
+
+use crate::err::{io_or_timeout, YeeError};
+use crate::notify::{parse_notification, PropertyChange};
+use crate::req::{Req, Response};
+
+// each response is sent along with its position in the stream, see `Changes`
+type Pending = Arc<Mutex<Option<HashMap<u16, Sender<(u64, Response)>>>>>;
+pub(crate) type Subscribers = Arc<Mutex<Vec<Sender<PropertyChange>>>>;
+
+/// Owns the connection to a light.
+///
+/// A reader thread routes each response to the request with the same id,
+/// so several requests can be in flight at once,
+/// and forwards notifications to the subscribers and the `Light` that owns the connection.
+#[derive(Debug)]
+pub(crate) struct Connection {
+    stream: TcpStream,
+    writer: BufWriter<TcpStream>,
+    // None once the reader has stopped
+    pending: Pending,
+    next_id: u16,
+    pub(crate) changes: Arc<Changes>,
+    reader: Option<JoinHandle<()>>,
+}
+
+// a light only has a few dozen properties, so a full queue means nobody is applying them
+const MAX_QUEUED_CHANGES: usize = 256;
+
+/// The notifications received on a connection that the `Light` hasn't applied yet.
+///
+/// Each change is kept with the position of its line in the stream,
+/// so a request only applies the changes the light reported before responding to it.
+/// Once `MAX_QUEUED_CHANGES` are waiting, a change replaces the waiting one for the same property,
+/// or else the oldest one.
+#[derive(Debug, Default)]
+pub(crate) struct Changes {
+    queue: Mutex<ChangeQueue>,
+    arrived: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct ChangeQueue {
+    changes: VecDeque<(u64, PropertyChange)>,
+    // set once the reader has stopped
+    closed: bool,
+}
+
+impl Changes {
+    pub(crate) fn push(&self, seq: u64, change: PropertyChange) {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.changes.len() >= MAX_QUEUED_CHANGES {
+            let replaced = queue.changes.iter().position(|(_, c)| c.property == change.property).unwrap_or(0);
+            queue.changes.remove(replaced);
+        }
+        queue.changes.push_back((seq, change));
+        self.arrived.notify_all();
+    }
+
+    pub(crate) fn close(&self) {
+        self.queue.lock().unwrap().closed = true;
+        self.arrived.notify_all();
+    }
+
+    /// Takes the changes received before the line at `seq`.
+    pub(crate) fn take_before(&self, seq: u64) -> Vec<PropertyChange> {
+        let mut queue = self.queue.lock().unwrap();
+        let count = queue.changes.iter().take_while(|(s, _)| *s < seq).count();
+        queue.changes.drain(..count).map(|(_, c)| c).collect()
+    }
+
+    pub(crate) fn take_all(&self) -> Vec<PropertyChange> {
+        self.take_before(u64::MAX)
+    }
+
+    /// Takes the oldest change if one is waiting, for `AsyncLight` which can't wait on the condvar.
+    #[cfg(feature = "async")]
+    pub(crate) fn try_recv(&self) -> Result<PropertyChange, mpsc::TryRecvError> {
+        let mut queue = self.queue.lock().unwrap();
+        match queue.changes.pop_front() {
+            Some((_, change)) => Ok(change),
+            None if queue.closed => Err(mpsc::TryRecvError::Disconnected),
+            None => Err(mpsc::TryRecvError::Empty)
+        }
+    }
+
+    /// Takes the oldest change, waiting up to `timeout` for one to arrive.
+    pub(crate) fn recv_timeout(&self, timeout: Duration) -> Result<PropertyChange, RecvTimeoutError> {
+        let end = Instant::now() + timeout;
+        let mut queue = self.queue.lock().unwrap();
+        loop {
+            if let Some((_, change)) = queue.changes.pop_front() {
+                return Ok(change);
+            } else if queue.closed {
+                return Err(RecvTimeoutError::Disconnected);
+            }
+            let remaining = end.saturating_duration_since(Instant::now());
+            if remaining == Duration::from_secs(0) {
+                return Err(RecvTimeoutError::Timeout);
+            }
+            queue = self.arrived.wait_timeout(queue, remaining).unwrap().0;
+        }
+    }
+}
+
+/// A request that was sent and is waiting for its response.
+#[derive(Debug)]
+pub(crate) struct PendingResponse {
+    id: u16,
+    receiver: Receiver<(u64, Response)>,
+    pending: Pending,
+}
+
+impl PendingResponse {
+    /// Waits for the response, for at most `timeout` if there is one.
+    pub(crate) fn wait(self, timeout: Option<Duration>) -> Result<Vec<Value>, YeeError> {
+        self.receive(timeout).and_then(|(_, res)| res.into_result())
+    }
+
+    /// Like `wait`, also returning the position of the response in the stream, for `Changes::take_before`.
+    pub(crate) fn receive(self, timeout: Option<Duration>) -> Result<(u64, Response), YeeError> {
+        let res = match timeout {
+            None => self.receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            Some(timeout) => self.receiver.recv_timeout(timeout)
+        };
+        match res {
+            Ok(res) => Ok(res),
+            Err(RecvTimeoutError::Timeout) => {
+                // a late response is dropped by the reader
+                if let Some(pending) = self.pending.lock().unwrap().as_mut() {
+                    pending.remove(&self.id);
+                }
+                Err(YeeError::Timeout { operation: "receive a response" })
+            }
+            // the reader stopped before the response arrived
+            Err(RecvTimeoutError::Disconnected) => Err(io::Error::from(io::ErrorKind::UnexpectedEof).into())
+        }
+    }
+}
+
+impl Connection {
+    pub(crate) fn new(stream: TcpStream, subscribers: Subscribers) -> Result<Connection, YeeError> {
+        let writer = BufWriter::new(stream.try_clone()?);
+        let reader = BufReader::new(stream.try_clone()?);
+        let pending: Pending = Arc::new(Mutex::new(Some(HashMap::new())));
+        let changes = Arc::new(Changes::default());
+
+        let reader_pending = pending.clone();
+        let reader_changes = changes.clone();
+        let reader = thread::spawn(move || read_loop(reader, reader_pending, reader_changes, subscribers));
+
+        Ok(Connection { stream, writer, pending, next_id: 0, changes, reader: Some(reader) })
+    }
+
+    /// Sends the request with a new id, unique among the requests still waiting for a response.
+    pub(crate) fn send(&mut self, mut req: Req) -> Result<PendingResponse, YeeError> {
+        let (sender, receiver) = mpsc::channel();
+        {
+            let mut pending = self.pending.lock().unwrap();
+            let pending = pending.as_mut()
+                .ok_or_else(|| YeeError::from(io::Error::from(io::ErrorKind::NotConnected)))?;
+            self.next_id = next_free_id(self.next_id, pending);
+            req.id = self.next_id;
+            pending.insert(req.id, sender);
+        }
+
+        let mut json = serde_json::to_string(&req).unwrap();
+        json.push_str("\r\n");
+        let written = self.writer.write_all(json.as_bytes()).and_then(|_| self.writer.flush());
+        if let Err(e) = written {
+            if let Some(pending) = self.pending.lock().unwrap().as_mut() {
+                pending.remove(&req.id);
+            }
+            return Err(io_or_timeout("send a request")(e));
+        }
+        Ok(PendingResponse { id: req.id, receiver, pending: self.pending.clone() })
+    }
+
+    pub(crate) fn set_write_timeout(&self, timeout: Option<Duration>) -> Result<(), YeeError> {
+        Ok(self.stream.set_write_timeout(timeout)?)
+    }
+
+    /// Whether the light closed the connection, after which every request fails.
+    pub(crate) fn is_closed(&self) -> bool {
+        self.pending.lock().unwrap().is_none()
+    }
+
+    pub(crate) fn local_addr(&self) -> Result<SocketAddr, YeeError> {
+        Ok(self.stream.local_addr()?)
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        // unblocks the reader
+        let _ = self.stream.shutdown(Shutdown::Both);
+        if let Some(reader) = self.reader.take() {
+            let _ = reader.join();
+        }
+    }
+}
+
+/// The id after `last` that no pending request is using.
+pub(crate) fn next_free_id<T>(last: u16, pending: &HashMap<u16, T>) -> u16 {
+    let mut id = last;
+    loop {
+        id = id.wrapping_add(1);
+        if !pending.contains_key(&id) {
+            return id;
+        }
+    }
+}
+
+fn read_loop(mut reader: BufReader<TcpStream>, pending: Pending,
+             changes: Arc<Changes>, subscribers: Subscribers) {
+    let mut line = String::new();
+    let mut seq: u64 = 0;
+    loop {
+        line.clear();
+        match reader.read_line(&mut line) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {}
+        }
+        seq += 1;
+        if let Ok(res) = serde_json::from_str::<Response>(&line) {
+            let sender = pending.lock().unwrap().as_mut().and_then(|p| p.remove(&res.id));
+            if let Some(sender) = sender {
+                let _ = sender.send((seq, res));
+            }
+        } else if let Some(new_changes) = parse_notification(&line) {
+            let mut subscribers = subscribers.lock().unwrap();
+            for change in new_changes {
+                // drop subscribers that hung up
+                subscribers.retain(|s| s.send(change.clone()).is_ok());
+                changes.push(seq, change);
+            }
+        }
+    }
+    // fails every request still waiting
+    pending.lock().unwrap().take();
+    changes.close();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddrV4, TcpListener};
+
+    use serde_json::json;
+
+    use crate::prop::{PropValue, Property};
+
+    use super::*;
+
+    #[test]
+    fn routes_out_of_order_responses() -> anyhow::Result<()> {
+        // given
+        let listener = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))?;
+        let addr = listener.local_addr()?;
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut writer = stream;
+            let mut ids = Vec::new();
+            for _ in 0..3 {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let req: Value = serde_json::from_str(&line).unwrap();
+                ids.push(req["id"].clone());
+            }
+            // answer in reverse, with a notification in between
+            write!(writer, "{{\"id\":{},\"result\":[\"third\"]}}\r\n", ids[2]).unwrap();
+            write!(writer, "{{\"method\":\"props\",\"params\":{{\"bright\":\"5\"}}}}\r\n").unwrap();
+            write!(writer, "{{\"id\":{},\"result\":[\"second\"]}}\r\n", ids[1]).unwrap();
+            write!(writer, "{{\"id\":{},\"result\":[\"first\"]}}\r\n", ids[0]).unwrap();
+            ids
+        });
+        let subscribers: Subscribers = Arc::new(Mutex::new(Vec::new()));
+        let mut conn = Connection::new(TcpStream::connect(addr)?, subscribers)?;
+
+        // when
+        let first = conn.send(Req::new("a".to_string(), vec![]))?;
+        let second = conn.send(Req::new("b".to_string(), vec![]))?;
+        let third = conn.send(Req::new("c".to_string(), vec![]))?;
+
+        // then
+        assert_eq!(first.wait(None)?, vec![json!("first")]);
+        assert_eq!(second.wait(None)?, vec![json!("second")]);
+        assert_eq!(third.wait(None)?, vec![json!("third")]);
+        let ids = handle.join().unwrap();
+        assert_ne!(ids[0], ids[1]);
+        assert_ne!(ids[1], ids[2]);
+        assert_eq!(conn.changes.take_all().len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn fails_pending_when_closed() -> anyhow::Result<()> {
+        // given
+        let listener = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))?;
+        let addr = listener.local_addr()?;
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+        });
+        let mut conn = Connection::new(TcpStream::connect(addr)?, Arc::new(Mutex::new(Vec::new())))?;
+
+        // when
+        let pending = conn.send(Req::new("a".to_string(), vec![]))?;
+        handle.join().unwrap();
+
+        // then
+        assert!(pending.wait(None).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn times_out_and_forgets_request() -> anyhow::Result<()> {
+        // given
+        let listener = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))?;
+        let addr = listener.local_addr()?;
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut writer = stream;
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let req: Value = serde_json::from_str(&line).unwrap();
+            thread::sleep(Duration::from_millis(150));
+            write!(writer, "{{\"id\":{},\"result\":[\"late\"]}}\r\n", req["id"]).unwrap();
+        });
+        let mut conn = Connection::new(TcpStream::connect(addr)?, Arc::new(Mutex::new(Vec::new())))?;
+
+        // when
+        let result = conn.send(Req::new("a".to_string(), vec![]))?.wait(Some(Duration::from_millis(50)));
+        handle.join().unwrap();
+
+        // then
+        assert!(matches!(result, Err(YeeError::Timeout { .. })));
+        assert!(conn.pending.lock().unwrap().as_ref().is_none_or(HashMap::is_empty));
+        Ok(())
+    }
+
+    #[test]
+    fn keeps_changes_in_bounds() {
+        // given
+        let changes = Changes::default();
+        let change = |property, bright| PropertyChange { property, value: PropValue::Bright(bright) };
+
+        // when
+        for i in 0..MAX_QUEUED_CHANGES {
+            changes.push(i as u64, change(Property::Bright, (i % 100) as u8));
+        }
+        changes.push(1000, change(Property::BgBright, 1));
+        changes.push(1001, change(Property::BgBright, 2));
+        let before_response = changes.take_before(MAX_QUEUED_CHANGES as u64 - 1);
+        let rest = changes.take_all();
+
+        // then
+        assert_eq!(before_response.len(), MAX_QUEUED_CHANGES - 2);
+        assert_eq!(before_response[0], change(Property::Bright, 1));
+        assert_eq!(rest, vec![change(Property::Bright, ((MAX_QUEUED_CHANGES - 1) % 100) as u8),
+                              change(Property::BgBright, 2)]);
+        assert_eq!(changes.recv_timeout(Duration::from_millis(10)), Err(RecvTimeoutError::Timeout));
+    }
+}
@@ -0,0 +1,300 @@
+use std::collections::HashSet;
+use std::io::ErrorKind;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use crate::{interface_of, parse_search_response, YeeClient};
+use crate::err::YeeError;
+use crate::light::Light;
+
+/// Everything that happened during a search, from `YeeClient::get_report`.
+///
+/// With the `async` feature, `AsyncYeeClient::get_report` returns a report of `AsyncLight`s.
+#[derive(Debug)]
+pub struct DiscoveryReport<L = Light> {
+    /// the lights found and connected to, without duplicates
+    pub lights: Vec<L>,
+    /// responses that didn't turn into a connected light
+    pub rejected: Vec<RejectedResponse>,
+    /// search messages that couldn't be sent
+    pub send_errors: Vec<YeeError>,
+}
+
+impl<L> Default for DiscoveryReport<L> {
+    fn default() -> Self {
+        DiscoveryReport { lights: Vec::new(), rejected: Vec::new(), send_errors: Vec::new() }
+    }
+}
+
+/// A response that wasn't from a valid light, or from a light that couldn't be connected to.
+#[derive(Debug)]
+pub struct RejectedResponse {
+    pub sender: SocketAddr,
+    pub error: YeeError,
+}
+
+/// A search in progress, from `YeeClient::discover`, yielding each light as soon as it's connected to.
+///
+/// Lights are yielded once per id, and the search ends with the timeout or the stop condition of its options.
+#[derive(Debug)]
+pub struct Discovery<'a> {
+    client: &'a YeeClient,
+    options: SearchOptions,
+    start: Instant,
+    probes: u32,
+    next_probe: Instant,
+    found: HashSet<String>,
+    // the lights that couldn't be connected to, rejected once rather than for every probe they answer
+    unreachable: HashSet<SocketAddr>,
+    rejected: Vec<RejectedResponse>,
+    send_errors: Vec<YeeError>,
+    done: bool,
+}
+
+impl<'a> Discovery<'a> {
+    pub(crate) fn new(client: &'a YeeClient, options: &SearchOptions) -> Discovery<'a> {
+        let start = Instant::now();
+        Discovery {
+            client,
+            options: options.clone(),
+            start,
+            probes: 0,
+            next_probe: start,
+            found: HashSet::new(),
+            unreachable: HashSet::new(),
+            rejected: Vec::new(),
+            send_errors: Vec::new(),
+            done: false,
+        }
+    }
+
+    /// The responses rejected so far.
+    pub fn rejected(&self) -> &[RejectedResponse] {
+        &self.rejected
+    }
+
+    /// The search messages that couldn't be sent so far.
+    pub fn send_errors(&self) -> &[YeeError] {
+        &self.send_errors
+    }
+
+    /// Runs the rest of the search, the report has the lights that weren't yielded yet.
+    pub fn into_report(mut self) -> DiscoveryReport {
+        let lights = self.by_ref().collect();
+        DiscoveryReport { lights, rejected: self.rejected, send_errors: self.send_errors }
+    }
+}
+
+impl Iterator for Discovery<'_> {
+    type Item = Light;
+
+    fn next(&mut self) -> Option<Light> {
+        while !self.done && self.start.elapsed() < self.options.timeout() {
+            if self.probes < self.options.probe_count() && Instant::now() >= self.next_probe {
+                self.client.send_probe(&mut self.send_errors);
+                self.probes += 1;
+                self.next_probe = Instant::now() + self.options.next_interval();
+            }
+            for seeker in &self.client.seekers {
+                let mut buf = [0u8; 1024];
+                let (size, sender) = match seeker.socket.recv_from(&mut buf) {
+                    Ok(received) => received,
+                    Err(_) => continue
+                };
+                let mut new_light = match parse_search_response(&buf[..size], &sender) {
+                    Ok(new_light) => new_light,
+                    Err(error) => {
+                        self.rejected.push(RejectedResponse { sender, error });
+                        continue;
+                    }
+                };
+                if self.found.contains(new_light.id()) || self.unreachable.contains(&sender) {
+                    continue;
+                }
+                let subnets = self.client.seekers.iter().map(|s| (s.interface, s.netmask));
+                new_light.interface = interface_of(subnets, seeker.interface, &sender);
+                // connecting is cut short by the end of the search
+                match new_light.with_deadline(self.start + self.options.timeout(), Light::init) {
+                    Ok(()) => {
+                        self.found.insert(new_light.id().to_string());
+                        self.done = self.options.is_satisfied(self.found.iter().map(String::as_str));
+                        return Some(new_light);
+                    }
+                    // still connecting when the search ended, like with `AsyncYeeClient::search`
+                    Err(YeeError::Timeout { .. }) if self.start.elapsed() >= self.options.timeout() => {}
+                    Err(error) => {
+                        self.unreachable.insert(sender);
+                        self.rejected.push(RejectedResponse { sender, error });
+                    }
+                }
+            }
+        }
+        self.done = true;
+        None
+    }
+}
+
+#[derive(Debug, Clone)]
+enum StopCondition {
+    Count(usize),
+    Ids(HashSet<String>),
+}
+
+/// How a search is made, for `YeeClient::search`.
+///
+/// By default a single search message is sent and the search lasts the whole timeout, like `get_report`.
+/// Since lights often miss a message, more can be sent during the timeout, and the search can end as soon as
+/// the expected lights are found.
+#[derive(Debug, Clone)]
+pub struct SearchOptions {
+    timeout: Duration,
+    probes: u32,
+    interval: Duration,
+    jitter: Duration,
+    stop: Option<StopCondition>,
+}
+
+impl SearchOptions {
+    pub fn new(timeout: Duration) -> SearchOptions {
+        SearchOptions {
+            timeout,
+            probes: 1,
+            interval: Duration::from_millis(500),
+            jitter: Duration::from_millis(100),
+            stop: None,
+        }
+    }
+
+    /// Sends `count` search messages, at least one, `interval` apart as long as the timeout allows.
+    pub fn probes(mut self, count: u32, interval: Duration) -> Self {
+        self.probes = count.max(1);
+        self.interval = interval;
+        self
+    }
+
+    /// Adds up to `jitter` to each interval, so that clients started together don't send at the same time.
+    pub fn jitter(mut self, jitter: Duration) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Ends the search once `count` lights are found.
+    pub fn until_found(mut self, count: usize) -> Self {
+        self.stop = Some(StopCondition::Count(count));
+        self
+    }
+
+    /// Ends the search once all the lights with `ids` are found.
+    pub fn until_ids<I, S>(mut self, ids: I) -> Self
+        where I: IntoIterator<Item=S>, S: Into<String> {
+        self.stop = Some(StopCondition::Ids(ids.into_iter().map(Into::into).collect()));
+        self
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    pub(crate) fn probe_count(&self) -> u32 {
+        self.probes
+    }
+
+    /// The time to wait before the next search message.
+    pub(crate) fn next_interval(&self) -> Duration {
+        let jitter = self.jitter.as_millis() as u64;
+        self.interval + Duration::from_millis(fastrand::u64(0..=jitter))
+    }
+
+    /// Whether the lights with `found` ids are enough to end the search early.
+    pub(crate) fn is_satisfied<'a>(&self, found: impl IntoIterator<Item=&'a str>) -> bool {
+        let found: HashSet<&str> = found.into_iter().collect();
+        match &self.stop {
+            None => false,
+            Some(StopCondition::Count(count)) => found.len() >= *count,
+            Some(StopCondition::Ids(ids)) => ids.iter().all(|id| found.contains(id.as_str()))
+        }
+    }
+}
+
+// larger subnets would take too long to sweep
+const MIN_SWEEP_PREFIX: u32 = 16;
+
+/// The hosts of an IPv4 subnet like `192.168.1.0/24`, without its network and broadcast addresses.
+pub(crate) fn subnet_hosts(cidr: &str) -> Result<Vec<Ipv4Addr>, YeeError> {
+    let invalid = || YeeError::InvalidValue { field_name: "cidr", value: cidr.to_string() };
+    let (addr, prefix) = cidr.split_once('/').ok_or_else(invalid)?;
+    let addr: Ipv4Addr = addr.trim().parse().map_err(|_| invalid())?;
+    let prefix: u32 = prefix.trim().parse().map_err(|_| invalid())?;
+    if !(MIN_SWEEP_PREFIX..=32).contains(&prefix) {
+        return Err(invalid());
+    }
+
+    let mask = u32::MAX << (32 - prefix);
+    let network = u32::from(addr) & mask;
+    let broadcast = network | !mask;
+    // a /31 or /32 has no network and broadcast addresses
+    let hosts = if prefix >= 31 { network..=broadcast } else { network + 1..=broadcast - 1 };
+    Ok(hosts.map(Ipv4Addr::from).collect())
+}
+
+/// Whether a probe failed only because there's no light at the address.
+pub(crate) fn is_silent(error: &YeeError) -> bool {
+    match error {
+        YeeError::Timeout { .. } => true,
+        YeeError::IoError { source } => source.kind() == ErrorKind::ConnectionRefused,
+        _ => false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stop_conditions() {
+        // given
+        let whole_timeout = SearchOptions::new(Duration::from_secs(1));
+        let two = SearchOptions::new(Duration::from_secs(1)).until_found(2);
+        let ids = SearchOptions::new(Duration::from_secs(1)).until_ids(vec!["0x1", "0x3"]);
+
+        // then
+        assert!(!whole_timeout.is_satisfied(vec!["0x1", "0x2", "0x3"]));
+        assert!(!two.is_satisfied(vec!["0x1", "0x1"]));
+        assert!(two.is_satisfied(vec!["0x1", "0x2"]));
+        assert!(!ids.is_satisfied(vec!["0x1", "0x2"]));
+        assert!(ids.is_satisfied(vec!["0x3", "0x2", "0x1"]));
+    }
+
+    #[test]
+    fn jittered_intervals() {
+        // given
+        let options = SearchOptions::new(Duration::from_secs(1))
+            .probes(0, Duration::from_millis(200))
+            .jitter(Duration::from_millis(50));
+
+        // then
+        assert_eq!(options.probe_count(), 1);
+        for _ in 0..100 {
+            let interval = options.next_interval();
+            assert!(interval >= Duration::from_millis(200) && interval <= Duration::from_millis(250));
+        }
+    }
+
+    #[test]
+    fn hosts_of_subnet() -> anyhow::Result<()> {
+        // when
+        let hosts = subnet_hosts("192.168.1.77/24")?;
+
+        // then
+        assert_eq!(hosts.len(), 254);
+        assert_eq!(hosts[0], Ipv4Addr::new(192, 168, 1, 1));
+        assert_eq!(hosts[253], Ipv4Addr::new(192, 168, 1, 254));
+        assert_eq!(subnet_hosts("10.0.0.5/32")?, vec![Ipv4Addr::new(10, 0, 0, 5)]);
+        assert_eq!(subnet_hosts("10.0.0.5/31")?.len(), 2);
+        assert_eq!(subnet_hosts("10.0.0.0/16")?.len(), 65534);
+        assert!(subnet_hosts("10.0.0.0/8").is_err());
+        assert!(subnet_hosts("10.0.0.0").is_err());
+        assert!(subnet_hosts("::1/128").is_err());
+        Ok(())
+    }
+}
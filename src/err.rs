@@ -1,6 +1,8 @@
 use std::error::Error;
 use std::fmt::{self, Display, Formatter};
+use std::io::{self, ErrorKind};
 use std::num::ParseIntError;
+use std::time::Duration;
 
 #[derive(Debug)]
 pub enum YeeError {
@@ -10,6 +12,14 @@ pub enum YeeError {
     MethodNotSupported { method_name: &'static str },
     InvalidValue { field_name: &'static str, value: String },
     ChangeFailed { message: String },
+    /// the light responded with an error
+    Device { code: i32, message: String },
+    /// the light didn't connect, accept a request or respond in time
+    Timeout { operation: &'static str },
+    /// the command quota of the light is used up
+    RateLimited { retry_after: Duration },
+    /// a message from a light couldn't be parsed
+    InvalidResponse { message: String },
 }
 
 impl Display for YeeError {
@@ -20,14 +30,22 @@ impl Display for YeeError {
             YeeError::IoError { .. } => "IoError",
             YeeError::MethodNotSupported { .. } => "MethodNotSupported",
             YeeError::InvalidValue { .. } => "InvalidValue",
-            YeeError::ChangeFailed { .. } => "ChangeFailed"
+            YeeError::ChangeFailed { .. } => "ChangeFailed",
+            YeeError::Device { .. } => "Device",
+            YeeError::Timeout { .. } => "Timeout",
+            YeeError::RateLimited { .. } => "RateLimited",
+            YeeError::InvalidResponse { .. } => "InvalidResponse"
         }, match self {
             YeeError::ParseFieldFailed { field_name, .. } => format!("failed to parse required field: {}", field_name),
             YeeError::FieldNotFound { field_name } => format!("did not find the required field: {}", field_name),
             YeeError::IoError { source } => format!("IO error: {}", source),
             YeeError::MethodNotSupported { method_name } => format!("cannot use method: {}", method_name),
             YeeError::InvalidValue { field_name, value } => format!("invalid value for {}: {}", field_name, value),
-            YeeError::ChangeFailed { message } => format!("changing param failed: {}", message)
+            YeeError::ChangeFailed { message } => format!("changing param failed: {}", message),
+            YeeError::Device { code, message } => format!("light returned error {}: {}", code, message),
+            YeeError::Timeout { operation } => format!("timed out waiting to {}", operation),
+            YeeError::RateLimited { retry_after } => format!("command quota used up, retry after {:?}", retry_after),
+            YeeError::InvalidResponse { message } => format!("invalid response: {}", message)
         })
     }
 }
@@ -48,6 +66,15 @@ impl From<std::io::Error> for YeeError {
     }
 }
 
+/// Maps an IO error to `YeeError::Timeout` if the operation timed out.
+pub(crate) fn io_or_timeout(operation: &'static str) -> impl Fn(io::Error) -> YeeError {
+    move |e| match e.kind() {
+        // a socket timeout shows up as WouldBlock on unix
+        ErrorKind::TimedOut | ErrorKind::WouldBlock => YeeError::Timeout { operation },
+        _ => e.into()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     #[test]
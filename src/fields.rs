@@ -3,7 +3,7 @@ use std::str::FromStr;
 
 use crate::err::YeeError;
 
-const HEX_FFFFFF: u32 = 16777215;
+pub(crate) const HEX_FFFFFF: u32 = 16777215;
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum PowerStatus {
@@ -41,6 +41,59 @@ impl FromStr for PowerStatus {
     }
 }
 
+/// The mode a light turns on into with `set_power`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PowerMode {
+    Normal,
+    ColorTemperature,
+    Color,
+    Hsv,
+    ColorFlow,
+    // only supported by ceiling lights
+    NightLight,
+}
+
+impl PowerMode {
+    pub fn value(&self) -> u8 {
+        match self {
+            Self::Normal => 0,
+            Self::ColorTemperature => 1,
+            Self::Color => 2,
+            Self::Hsv => 3,
+            Self::ColorFlow => 4,
+            Self::NightLight => 5
+        }
+    }
+}
+
+/// Whether a ceiling light is in its normal or night light mode.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ActiveMode {
+    Daylight,
+    Moonlight,
+}
+
+impl Display for ActiveMode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", match self {
+            Self::Daylight => "daylight",
+            Self::Moonlight => "moonlight"
+        })
+    }
+}
+
+impl FromStr for ActiveMode {
+    type Err = YeeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "0" => Ok(Self::Daylight),
+            "1" => Ok(Self::Moonlight),
+            _ => Err(YeeError::ParseFieldFailed { field_name: "active_mode", source: None })
+        }
+    }
+}
+
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum ColorMode {
@@ -154,6 +207,19 @@ mod tests {
         assert!(incorrect_parsed.is_err());
     }
 
+    #[test]
+    fn parse_active_mode() {
+        // when
+        let daylight = "0".parse::<ActiveMode>();
+        let moonlight = "1".parse::<ActiveMode>();
+        let incorrect = "2".parse::<ActiveMode>();
+
+        // then
+        assert_eq!(daylight.ok(), Some(ActiveMode::Daylight));
+        assert_eq!(moonlight.ok(), Some(ActiveMode::Moonlight));
+        assert!(incorrect.is_err());
+    }
+
     #[test]
     fn correct_parse_color_mode() -> anyhow::Result<()> {
         // given
@@ -0,0 +1,249 @@
+use std::convert::TryFrom;
+use std::fmt::{self, Display, Formatter};
+use std::time::Duration;
+
+use crate::err::YeeError;
+use crate::fields::{HEX_FFFFFF, Rgb};
+
+/// What the light does once a flow has finished.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum FlowAction {
+    /// go back to the state before the flow started
+    Recover,
+    /// stay at the last state of the flow
+    Stay,
+    /// turn the light off
+    Off,
+}
+
+impl FlowAction {
+    pub fn value(&self) -> u8 {
+        match self {
+            Self::Recover => 0,
+            Self::Stay => 1,
+            Self::Off => 2
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum FlowMode {
+    Color,
+    ColorTemperature,
+    Sleep,
+}
+
+impl FlowMode {
+    pub fn value(&self) -> u8 {
+        match self {
+            Self::Color => 1,
+            Self::ColorTemperature => 2,
+            Self::Sleep => 7
+        }
+    }
+
+    fn from_value(value: u8) -> Option<FlowMode> {
+        match value {
+            1 => Some(Self::Color),
+            2 => Some(Self::ColorTemperature),
+            7 => Some(Self::Sleep),
+            _ => None
+        }
+    }
+}
+
+/// A single state change in a `Flow`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct FlowStep {
+    // minimum 50ms
+    duration: Duration,
+    mode: FlowMode,
+    // rgb number or color temperature, ignored for FlowMode::Sleep
+    value: u32,
+    // None keeps the current brightness, ignored for FlowMode::Sleep
+    brightness: Option<u8>,
+}
+
+impl FlowStep {
+    pub fn new(duration: Duration, mode: FlowMode, value: u32, brightness: Option<u8>) -> Result<FlowStep, YeeError> {
+        if duration < Duration::from_millis(50) || duration.as_millis() > u64::MAX as u128 {
+            return Err(YeeError::InvalidValue { field_name: "duration", value: format!("{:?}", duration) });
+        }
+        match mode {
+            FlowMode::Color if value > HEX_FFFFFF =>
+                return Err(YeeError::InvalidValue { field_name: "rgb", value: value.to_string() }),
+            FlowMode::ColorTemperature if !(1700..=6500).contains(&value) =>
+                return Err(YeeError::InvalidValue { field_name: "ct", value: value.to_string() }),
+            _ => {}
+        }
+        if let Some(bright) = brightness {
+            if mode != FlowMode::Sleep && !(1..=100).contains(&bright) {
+                return Err(YeeError::InvalidValue { field_name: "bright", value: bright.to_string() });
+            }
+        }
+        let (value, brightness) = match mode {
+            FlowMode::Sleep => (0, None),
+            _ => (value, brightness)
+        };
+        Ok(FlowStep { duration, mode, value, brightness })
+    }
+
+    pub fn rgb(duration: Duration, rgb: Rgb, brightness: Option<u8>) -> Result<FlowStep, YeeError> {
+        Self::new(duration, FlowMode::Color, rgb.get_num(), brightness)
+    }
+
+    pub fn ct(duration: Duration, ct: u16, brightness: Option<u8>) -> Result<FlowStep, YeeError> {
+        Self::new(duration, FlowMode::ColorTemperature, ct as u32, brightness)
+    }
+
+    pub fn sleep(duration: Duration) -> Result<FlowStep, YeeError> {
+        Self::new(duration, FlowMode::Sleep, 0, None)
+    }
+
+    pub fn duration(&self) -> Duration {
+        self.duration
+    }
+
+    pub fn mode(&self) -> FlowMode {
+        self.mode
+    }
+
+    pub fn value(&self) -> u32 {
+        self.value
+    }
+
+    pub fn brightness(&self) -> Option<u8> {
+        self.brightness
+    }
+}
+
+impl Display for FlowStep {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let brightness = self.brightness.map(|b| b as i16).unwrap_or(-1);
+        write!(f, "{},{},{},{}", self.duration.as_millis(), self.mode.value(), self.value, brightness)
+    }
+}
+
+/// A color flow, sent with `start_cf`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Flow {
+    // number of state changes before stopping, 0 means forever
+    count: u32,
+    action: FlowAction,
+    steps: Vec<FlowStep>,
+}
+
+impl Flow {
+    pub fn new(count: u32, action: FlowAction, steps: Vec<FlowStep>) -> Result<Flow, YeeError> {
+        if steps.is_empty() {
+            return Err(YeeError::InvalidValue { field_name: "flow_expression", value: String::new() });
+        }
+        Ok(Flow { count, action, steps })
+    }
+
+    /// Reads a flow expression, e.g. `1000,2,2700,100,500,1,255,10`.
+    pub fn parse(count: u32, action: FlowAction, expression: &str) -> Result<Flow, YeeError> {
+        let parse_err = |e| YeeError::ParseFieldFailed { field_name: "flow_expression", source: Some(e) };
+        let values = expression.split(',')
+            .map(|v| v.trim().parse::<i64>().map_err(parse_err))
+            .collect::<Result<Vec<i64>, YeeError>>()?;
+        if values.len() % 4 != 0 {
+            return Err(YeeError::ParseFieldFailed { field_name: "flow_expression", source: None });
+        }
+
+        let invalid = || YeeError::InvalidValue { field_name: "flow_expression", value: expression.to_string() };
+        let steps = values.chunks(4)
+            .map(|tuple| {
+                let duration = u64::try_from(tuple[0]).map_err(|_| invalid())?;
+                let mode = u8::try_from(tuple[1]).ok()
+                    .and_then(FlowMode::from_value)
+                    .ok_or_else(invalid)?;
+                let value = u32::try_from(tuple[2]).map_err(|_| invalid())?;
+                let brightness = match tuple[3] {
+                    -1 => None,
+                    b => Some(u8::try_from(b).map_err(|_| invalid())?)
+                };
+                FlowStep::new(Duration::from_millis(duration), mode, value, brightness)
+            })
+            .collect::<Result<Vec<FlowStep>, YeeError>>()?;
+        Self::new(count, action, steps)
+    }
+
+    /// The comma-separated flow expression sent to the light.
+    pub fn expression(&self) -> String {
+        self.steps.iter()
+            .map(|s| s.to_string())
+            .collect::<Vec<String>>()
+            .join(",")
+    }
+
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+
+    pub fn action(&self) -> FlowAction {
+        self.action
+    }
+
+    pub fn steps(&self) -> &[FlowStep] {
+        &self.steps
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn correct_expression() -> anyhow::Result<()> {
+        // given
+        let steps = vec![
+            FlowStep::ct(Duration::from_millis(1000), 2700, Some(100))?,
+            FlowStep::rgb(Duration::from_millis(500), Rgb::new(0, 0, 255), None)?,
+            FlowStep::sleep(Duration::from_secs(5))?,
+        ];
+
+        // when
+        let flow = Flow::new(4, FlowAction::Stay, steps)?;
+
+        // then
+        assert_eq!(flow.expression(), "1000,2,2700,100,500,1,255,-1,5000,7,0,-1");
+        Ok(())
+    }
+
+    #[test]
+    fn parse_round_trips() -> anyhow::Result<()> {
+        // given
+        let expression = "1000,2,2700,100,500,1,255,10,5000,7,0,-1";
+
+        // when
+        let flow = Flow::parse(0, FlowAction::Recover, expression)?;
+
+        // then
+        assert_eq!(flow.steps().len(), 3);
+        assert_eq!(flow.steps()[1].mode(), FlowMode::Color);
+        assert_eq!(flow.steps()[1].brightness(), Some(10));
+        assert_eq!(flow.expression(), expression);
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_steps() {
+        // then
+        assert!(FlowStep::sleep(Duration::from_millis(49)).is_err());
+        assert!(FlowStep::ct(Duration::from_secs(1), 1699, None).is_err());
+        assert!(FlowStep::ct(Duration::from_secs(1), 3000, Some(0)).is_err());
+        assert!(FlowStep::new(Duration::from_secs(1), FlowMode::Color, HEX_FFFFFF + 1, None).is_err());
+        assert!(Flow::new(0, FlowAction::Off, vec![]).is_err());
+    }
+
+    #[test]
+    fn invalid_expression() {
+        // then
+        assert!(Flow::parse(0, FlowAction::Recover, "1000,2,2700").is_err());
+        assert!(Flow::parse(0, FlowAction::Recover, "1000,3,2700,100").is_err());
+        assert!(Flow::parse(0, FlowAction::Recover, "1000,2,2700,-5").is_err());
+        assert!(Flow::parse(0, FlowAction::Recover, "a,b,c,d").is_err());
+        assert!(Flow::parse(0, FlowAction::Recover, "").is_err());
+    }
+}
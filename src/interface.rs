@@ -0,0 +1,76 @@
+use std::net::{IpAddr, Ipv4Addr};
+
+use if_addrs::IfAddr;
+
+use crate::err::YeeError;
+
+/// A local network interface to search on, by one of its IPv4 addresses or by name, e.g. `eth0`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Interface {
+    Addr(Ipv4Addr),
+    Name(String),
+}
+
+impl Interface {
+    /// The IPv4 address of the interface, looking it up by name if needed.
+    pub fn addr(&self) -> Result<Ipv4Addr, YeeError> {
+        match self {
+            Interface::Addr(addr) => Ok(*addr),
+            Interface::Name(name) => if_addrs::get_if_addrs()?.into_iter()
+                .filter(|interface| &interface.name == name)
+                .find_map(|interface| match interface.ip() {
+                    IpAddr::V4(ip) => Some(ip),
+                    IpAddr::V6(_) => None
+                })
+                .ok_or_else(|| YeeError::InvalidValue { field_name: "interface", value: name.clone() })
+        }
+    }
+}
+
+/// The netmask of the local interface with the address `addr`, if there is one.
+pub(crate) fn netmask(addr: Ipv4Addr) -> Option<Ipv4Addr> {
+    if_addrs::get_if_addrs().ok()?.into_iter().find_map(|interface| match interface.addr {
+        IfAddr::V4(v4) if v4.ip == addr => Some(v4.netmask),
+        _ => None
+    })
+}
+
+impl From<Ipv4Addr> for Interface {
+    fn from(addr: Ipv4Addr) -> Self {
+        Interface::Addr(addr)
+    }
+}
+
+/// An address like `192.168.1.2`, or else a name like `eth0`.
+impl From<&str> for Interface {
+    fn from(s: &str) -> Self {
+        match s.parse() {
+            Ok(addr) => Interface::Addr(addr),
+            Err(_) => Interface::Name(s.to_string())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve_addr_and_name() -> anyhow::Result<()> {
+        // given
+        let loopback = if_addrs::get_if_addrs()?.into_iter()
+            .find(|interface| interface.ip() == IpAddr::V4(Ipv4Addr::LOCALHOST))
+            .expect("no loopback interface");
+
+        // when
+        let by_name = Interface::from(loopback.name.as_str()).addr()?;
+        let by_addr = Interface::from("127.0.0.1").addr()?;
+
+        // then
+        assert_eq!(by_name, Ipv4Addr::LOCALHOST);
+        assert_eq!(by_addr, Ipv4Addr::LOCALHOST);
+        assert!(matches!(Interface::from("no-such-interface0").addr(),
+                         Err(YeeError::InvalidValue { field_name: "interface", .. })));
+        Ok(())
+    }
+}
@@ -2,20 +2,42 @@
 //!
 //!
 //! module level doc!!
-use std::collections::{HashMap, HashSet};
-use std::net::{Ipv4Addr, SocketAddrV4, UdpSocket};
-use std::time::{Duration, Instant};
-
-use crate::err::YeeError;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, UdpSocket};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use crate::builder::YeeClientBuilder;
+use crate::discovery::{Discovery, DiscoveryReport, is_silent, RejectedResponse, SearchOptions, subnet_hosts};
+use crate::err::{io_or_timeout, YeeError};
+use crate::interface::Interface;
 use crate::light::Light;
 
 pub mod light;
 pub mod fields;
 pub mod err;
 pub mod req;
+pub mod prop;
+pub mod flow;
+pub mod scene;
+pub mod music;
+pub mod background;
+pub mod notify;
+pub mod retry;
+pub mod limit;
+pub mod monitor;
+pub mod discovery;
+pub mod builder;
+pub mod interface;
+#[cfg(feature = "async")]
+pub mod asynchronous;
+mod conn;
 
 pub const MULTICAST_ADDR: Ipv4Addr = Ipv4Addr::new(239, 255, 255, 250);
 pub const MULTICAST_PORT: u16 = 1982;
+/// The link-local SSDP group, for lights on IPv6 networks.
+pub const MULTICAST_ADDR_V6: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0xc);
 pub const ALL_LOCAL: Ipv4Addr = Ipv4Addr::new(0, 0, 0, 0);
 pub const DEFAULT_LOCAL_PORT: u16 = 7821;
 
@@ -25,73 +47,281 @@ pub const SEARCH_MSG: &str = "\
     MAN: \"ssdp:discover\"\r\n\
     ST: wifi_bulb";
 
+pub const SEARCH_MSG_V6: &str = "\
+    M-SEARCH * HTTP/1.1\r\n\
+    HOST: [ff02::c]:1982\r\n\
+    MAN: \"ssdp:discover\"\r\n\
+    ST: wifi_bulb";
+
+/// The `ST` header lights respond to.
+pub const DEFAULT_SEARCH_TARGET: &str = "wifi_bulb";
+
 #[derive(Debug)]
 pub struct YeeClient {
-    seeker: UdpSocket,
-    multicast_addr: SocketAddrV4,
+    seekers: Vec<Seeker>,
+    multicast_addr: SocketAddr,
+    search_msg: String,
+}
+
+// a socket searching on one chosen interface, or on the one the kernel picks
+#[derive(Debug)]
+pub(crate) struct Seeker {
+    pub(crate) socket: UdpSocket,
+    pub(crate) interface: Option<Ipv4Addr>,
+    // of the interface, to tell which one a response came in on
+    pub(crate) netmask: Option<Ipv4Addr>,
 }
 
 impl YeeClient {
     pub fn new() -> Result<YeeClient, YeeError> {
-        let addr = SocketAddrV4::new(MULTICAST_ADDR, MULTICAST_PORT);
-        Self::with_addr(addr, DEFAULT_LOCAL_PORT)
+        Self::builder().build()
+    }
+
+    /// For the socket options `new` and the other shortcuts don't have, e.g. an ephemeral port.
+    pub fn builder() -> YeeClientBuilder {
+        YeeClientBuilder::new()
     }
 
     pub fn with_addr(multicast_addr: SocketAddrV4, local_port: u16) -> Result<YeeClient, YeeError> {
-        // we don't know the IPs of the lights, so listen to all traffic
-        let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, local_port))?;
-        socket.join_multicast_v4(multicast_addr.ip(), &Ipv4Addr::UNSPECIFIED)?;
-        socket.set_nonblocking(true)?;
+        Self::builder().multicast_addr(multicast_addr.into()).local_port(local_port).build()
+    }
+
+    /// Searches the default group on each of `interfaces` at once, instead of the one the kernel picks.
+    pub fn on_interfaces(interfaces: &[Interface]) -> Result<YeeClient, YeeError> {
+        Self::builder().interfaces(interfaces).build()
+    }
 
-        Ok(YeeClient { seeker: socket, multicast_addr })
+    /// Like `with_addr`, with one socket joining the group on each of `interfaces`, all on `local_port`.
+    ///
+    /// The lights found record the interface they were found on, see `Light::interface`.
+    pub fn with_interfaces(multicast_addr: SocketAddrV4, local_port: u16, interfaces: &[Interface])
+                           -> Result<YeeClient, YeeError> {
+        if interfaces.is_empty() {
+            return Err(YeeError::InvalidValue { field_name: "interfaces", value: "[]".to_string() });
+        }
+        Self::builder().multicast_addr(multicast_addr.into()).local_port(local_port).interfaces(interfaces).build()
+    }
+
+    /// Searches the IPv6 link-local group on the given interface index, or the default interface with 0.
+    pub fn new_v6(interface: u32) -> Result<YeeClient, YeeError> {
+        let addr = SocketAddrV6::new(MULTICAST_ADDR_V6, MULTICAST_PORT, 0, interface);
+        Self::with_addr_v6(addr, DEFAULT_LOCAL_PORT)
+    }
+
+    /// Like `with_addr`, joining the group on the interface given by the scope id of `multicast_addr`.
+    pub fn with_addr_v6(multicast_addr: SocketAddrV6, local_port: u16) -> Result<YeeClient, YeeError> {
+        Self::builder().multicast_addr(multicast_addr.into()).local_port(local_port).build()
     }
 
+    /// Searches for `timeout`, returning the lights that were found and connected to.
+    ///
+    /// See `get_report` for what went wrong with the others.
     pub fn get_response(&self, timeout: Duration) -> Vec<Light> {
-        // TODO: handle send multicast fail
-        self.seeker.send_to(SEARCH_MSG.as_bytes(), &self.multicast_addr).unwrap();
-
-        let mut lights: HashSet<Light> = HashSet::new();
-        let now = Instant::now();
-        while now.elapsed() < timeout {
-            // all lifetimes depend on this buf
-            let mut buf = [0u8; 1024];
-            let mut headers = [httparse::EMPTY_HEADER; 17];
-            let mut res = httparse::Response::new(&mut headers);
-
-            if let Ok((size, _)) = self.seeker.recv_from(&mut buf) {
-                // TODO: handle if failed to parse response
-                res.parse(&buf[..size]).unwrap();
-                let headers: HashMap<&str, _> = res.headers.iter()
-                    .map(|h| {
-                        let name = h.name;
-                        let value = String::from_utf8_lossy(h.value);
-                        (name, value)
-                    }).collect();
-                if let Ok(mut new_light) = Light::from_fields(&headers) {
-                    if !lights.contains(&new_light) {
-                        if let Ok(()) = new_light.init() {
-                            lights.insert(new_light);
-                        }
+        self.get_report(timeout).lights
+    }
+
+    /// Searches for `timeout`, returning the lights found along with the responses that were rejected.
+    pub fn get_report(&self, timeout: Duration) -> DiscoveryReport {
+        self.search(&SearchOptions::new(timeout))
+    }
+
+    /// Like `get_report`, sending search messages and ending early as set in `options`.
+    pub fn search(&self, options: &SearchOptions) -> DiscoveryReport {
+        self.discover(options).into_report()
+    }
+
+    /// Searches as set in `options`, yielding each light as soon as it answers instead of waiting out the timeout.
+    pub fn discover(&self, options: &SearchOptions) -> Discovery<'_> {
+        Discovery::new(self, options)
+    }
+
+    /// Sends the search message straight to port 1982 of `addr`, for a light that multicast doesn't reach.
+    pub fn probe(&self, addr: IpAddr, timeout: Duration) -> Result<Light, YeeError> {
+        self.probe_at(SocketAddr::new(addr, MULTICAST_PORT), timeout)
+    }
+
+    /// Probes every host of an IPv4 subnet like `192.168.1.0/24`, up to `concurrency` at a time.
+    ///
+    /// Hosts that don't answer within `timeout` aren't reported, while lights that answer but can't be connected to
+    /// are rejected. Subnets larger than a /16 are rejected too.
+    pub fn sweep(&self, cidr: &str, timeout: Duration, concurrency: usize) -> Result<DiscoveryReport, YeeError> {
+        self.sweep_port(cidr, MULTICAST_PORT, timeout, concurrency)
+    }
+
+    fn sweep_port(&self, cidr: &str, port: u16, timeout: Duration, concurrency: usize)
+                  -> Result<DiscoveryReport, YeeError> {
+        let hosts = subnet_hosts(cidr)?;
+        let workers = concurrency.clamp(1, hosts.len());
+        let hosts = Mutex::new(hosts.into_iter());
+        let report = Mutex::new(DiscoveryReport::default());
+        thread::scope(|scope| {
+            for _ in 0..workers {
+                scope.spawn(|| loop {
+                    let host = match hosts.lock().unwrap().next() {
+                        Some(host) => host,
+                        None => break
+                    };
+                    let target = SocketAddr::new(host.into(), port);
+                    // only a host that doesn't answer is left out, a light that can't be connected to is reported
+                    let result = match unicast_search(target, &self.search_msg, timeout) {
+                        Ok(mut light) => light.init().map(|_| light),
+                        Err(error) if is_silent(&error) => continue,
+                        Err(error) => Err(error)
+                    };
+                    let mut report = report.lock().unwrap();
+                    match result {
+                        Ok(light) => report.lights.push(light),
+                        Err(error) => report.rejected.push(RejectedResponse { sender: target, error })
                     }
-                }
+                });
+            }
+        });
+
+        let mut report = report.into_inner().unwrap();
+        report.lights.sort_by_key(|light| *light.location());
+        Ok(report)
+    }
+
+    fn probe_at(&self, target: SocketAddr, timeout: Duration) -> Result<Light, YeeError> {
+        let mut light = unicast_search(target, &self.search_msg, timeout)?;
+        light.init()?;
+        Ok(light)
+    }
+
+    fn send_probe(&self, errors: &mut Vec<YeeError>) {
+        for seeker in &self.seekers {
+            if let Err(e) = seeker.socket.send_to(self.search_msg.as_bytes(), self.multicast_addr) {
+                errors.push(e.into());
             }
         }
-        let lights: Vec<Light> = lights.into_iter().collect();
-        lights
     }
 }
 
+/// Sends the search message to `target` alone and reads the light from its response, without connecting to it.
+pub(crate) fn unicast_search(target: SocketAddr, search_msg: &str, timeout: Duration) -> Result<Light, YeeError> {
+    // connected, so only the response of the target is received
+    let socket = UdpSocket::bind(any_local_addr(&target))?;
+    socket.connect(target)?;
+    socket.set_read_timeout(Some(timeout))?;
+    socket.send(search_msg.as_bytes())?;
+
+    let mut buf = [0u8; 1024];
+    let size = socket.recv(&mut buf).map_err(io_or_timeout("probe"))?;
+    parse_search_response(&buf[..size], &target)
+}
+
+/// The interface a response from `sender` came in on, i.e. the one of `subnets` with `sender` on it,
+/// or else `received_on`, the interface of the seeker that received it.
+///
+/// Seekers sharing a port all bind the unspecified address, so the OS can hand a response to any of them.
+pub(crate) fn interface_of<I>(subnets: I, received_on: Option<Ipv4Addr>, sender: &SocketAddr) -> Option<Ipv4Addr>
+    where I: IntoIterator<Item=(Option<Ipv4Addr>, Option<Ipv4Addr>)> {
+    let sender = match sender {
+        SocketAddr::V4(sender) => u32::from(*sender.ip()),
+        SocketAddr::V6(_) => return received_on
+    };
+    subnets.into_iter()
+        .find_map(|subnet| match subnet {
+            (Some(addr), Some(netmask)) if u32::from(addr) & u32::from(netmask) == sender & u32::from(netmask) =>
+                Some(addr),
+            _ => None
+        })
+        .or(received_on)
+}
+
+/// Any interface and port of the family of `target`, to probe it from.
+pub(crate) fn any_local_addr(target: &SocketAddr) -> SocketAddr {
+    match target {
+        SocketAddr::V4(_) => SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, 0, 0, 0).into()
+    }
+}
+
+/// The search message for the group at the given address, `SEARCH_MSG` or `SEARCH_MSG_V6` with the default target.
+pub(crate) fn search_msg(multicast_addr: &SocketAddr, search_target: &str) -> String {
+    let host = match multicast_addr {
+        SocketAddr::V4(_) => "239.255.255.250:1982",
+        SocketAddr::V6(_) => "[ff02::c]:1982"
+    };
+    format!("M-SEARCH * HTTP/1.1\r\nHOST: {}\r\nMAN: \"ssdp:discover\"\r\nST: {}", host, search_target)
+}
+
+/// Reads the light a search response from `sender` is from, without connecting to it.
+pub(crate) fn parse_search_response(buf: &[u8], sender: &SocketAddr) -> Result<Light, YeeError> {
+    let mut headers = [httparse::EMPTY_HEADER; 32];
+    let mut res = httparse::Response::new(&mut headers);
+    // lights don't end the headers with an empty line, so a partial parse is expected
+    res.parse(buf).map_err(|e| YeeError::InvalidResponse { message: e.to_string() })?;
+    let headers: HashMap<&str, _> = res.headers.iter()
+        .map(|h| {
+            let name = h.name;
+            let value = String::from_utf8_lossy(h.value);
+            (name, value)
+        }).collect();
+    let mut light = Light::from_fields(&headers)?;
+    light.scope_to(sender);
+    Ok(light)
+}
+
 #[cfg(test)]
 mod tests {
-    use std::net::{IpAddr, TcpListener};
+    use std::net::TcpListener;
+    use std::time::Instant;
 
     use super::*;
 
+    fn client(seeker: UdpSocket, multicast_addr: SocketAddr) -> YeeClient {
+        let search_msg = search_msg(&multicast_addr, DEFAULT_SEARCH_TARGET);
+        YeeClient { seekers: vec![Seeker { socket: seeker, interface: None, netmask: None }], multicast_addr, search_msg }
+    }
+
+    pub(crate) fn search_response(location: SocketAddr) -> String {
+        format!("HTTP/1.1 200 OK\r
+Location: yeelight://{}\r
+id: 0x12345abcde\r
+model: mono\r
+fw_ver: 20\r
+support: get_prop set_power\r
+power: on\r
+bright: 100\r
+color_mode: 2\r
+ct: 0\r
+rgb: 23\r
+hue: 34\r
+sat: 45\r
+name: light_one\r\n", location)
+    }
+
     #[test]
     fn is_multicast() {
         assert!(MULTICAST_ADDR.is_multicast());
     }
 
+    #[test]
+    fn default_search_messages() {
+        let v4: SocketAddr = SocketAddrV4::new(MULTICAST_ADDR, MULTICAST_PORT).into();
+        let v6: SocketAddr = SocketAddrV6::new(MULTICAST_ADDR_V6, MULTICAST_PORT, 0, 0).into();
+        assert_eq!(search_msg(&v4, DEFAULT_SEARCH_TARGET), SEARCH_MSG);
+        assert_eq!(search_msg(&v6, DEFAULT_SEARCH_TARGET), SEARCH_MSG_V6);
+    }
+
+    #[test]
+    fn scopes_link_local_location_to_sender() -> anyhow::Result<()> {
+        // given
+        let unscoped: SocketAddr = "[fe80::1]:55443".parse()?;
+        let sender: SocketAddr = SocketAddrV6::new("fe80::1".parse()?, MULTICAST_PORT, 0, 2).into();
+        let global: SocketAddr = "[2001:db8::1]:55443".parse()?;
+
+        // when
+        let scoped = parse_search_response(search_response(unscoped).as_bytes(), &sender)?;
+        let unchanged = parse_search_response(search_response(global).as_bytes(), &sender)?;
+
+        // then
+        assert!(matches!(scoped.location(), SocketAddr::V6(location) if location.scope_id() == 2));
+        assert_eq!(unchanged.location(), &global);
+        Ok(())
+    }
+
     #[test]
     fn create_valid_client() {
         // given
@@ -106,9 +336,9 @@ mod tests {
         // then
         assert!(client.is_ok());
         let client = client.unwrap();
-        assert_eq!(client.multicast_addr, sock_addr);
+        assert_eq!(client.multicast_addr, SocketAddr::V4(sock_addr));
 
-        let local_addr = client.seeker.local_addr();
+        let local_addr = client.seekers[0].socket.local_addr();
         assert!(local_addr.is_ok());
         let local_addr = local_addr.unwrap();
         assert_eq!(local_addr.ip(), IpAddr::V4(Ipv4Addr::UNSPECIFIED));
@@ -124,10 +354,10 @@ mod tests {
         assert!(client.is_ok());
         let client = client.unwrap();
 
-        assert_eq!(client.multicast_addr.ip(), &MULTICAST_ADDR);
+        assert_eq!(client.multicast_addr.ip(), IpAddr::V4(MULTICAST_ADDR));
         assert_eq!(client.multicast_addr.port(), MULTICAST_PORT);
 
-        let local_addr = client.seeker.local_addr();
+        let local_addr = client.seekers[0].socket.local_addr();
         assert!(local_addr.is_ok());
         let local_addr = local_addr.unwrap();
         assert_eq!(local_addr.ip(), IpAddr::V4(Ipv4Addr::UNSPECIFIED));
@@ -147,6 +377,55 @@ mod tests {
         assert!(client.is_err());
     }
 
+    #[test]
+    fn create_interface_client() -> anyhow::Result<()> {
+        // given
+        let multicast_addr = SocketAddrV4::new(MULTICAST_ADDR, MULTICAST_PORT);
+        let interfaces = [Interface::from("127.0.0.1")];
+
+        // when
+        let client = YeeClient::with_interfaces(multicast_addr, 0, &interfaces)?;
+
+        // then
+        assert_eq!(client.seekers.len(), 1);
+        assert_eq!(client.seekers[0].interface, Some(Ipv4Addr::LOCALHOST));
+        assert_eq!(client.seekers[0].socket.local_addr()?.ip(), IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        assert!(YeeClient::with_interfaces(multicast_addr, 0, &[]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn create_v6_client() -> anyhow::Result<()> {
+        // given
+        let multicast_addr = SocketAddrV6::new(MULTICAST_ADDR_V6, MULTICAST_PORT, 0, 0);
+
+        // when
+        let client = YeeClient::with_addr_v6(multicast_addr, 0)?;
+
+        // then
+        assert_eq!(client.multicast_addr, SocketAddr::V6(multicast_addr));
+        assert!(client.seekers[0].socket.local_addr()?.is_ipv6());
+        Ok(())
+    }
+
+    #[test]
+    fn send_v6_message() -> anyhow::Result<()> {
+        // given
+        let multicast_listener = UdpSocket::bind("[::1]:0")?;
+        let fake_sender = UdpSocket::bind("[::1]:0")?;
+        fake_sender.set_nonblocking(true)?;
+        let client = client(fake_sender, multicast_listener.local_addr()?);
+
+        // when
+        client.get_response(Duration::from_millis(100));
+
+        // then
+        let mut recv_buffer = [0; 512];
+        let (size, _) = multicast_listener.recv_from(&mut recv_buffer)?;
+        assert_eq!(&recv_buffer[..size], SEARCH_MSG_V6.as_bytes());
+        Ok(())
+    }
+
     #[test]
     fn send_correct_message() -> anyhow::Result<()> {
         // given
@@ -157,7 +436,7 @@ mod tests {
         let multicast_listener = UdpSocket::bind(fake_multicast_addr)?;
         let fake_sender = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, client_port))?;
         fake_sender.set_nonblocking(true)?;
-        let client = YeeClient { seeker: fake_sender, multicast_addr: fake_multicast_addr };
+        let client = client(fake_sender, fake_multicast_addr.into());
 
         // when
         client.get_response(Duration::from_millis(500));
@@ -185,7 +464,7 @@ mod tests {
         let fake_sender = UdpSocket::bind(client_addr)?;
 
         fake_sender.set_nonblocking(true)?;
-        let client = YeeClient { seeker: fake_sender, multicast_addr: fake_multicast_addr };
+        let client = client(fake_sender, fake_multicast_addr.into());
 
         // send mock messages
         let fake_addr_1 = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 9889);
@@ -269,7 +548,7 @@ name: light_one\r\n";
 
         // THEN
         assert_eq!(result.len(), 3);
-        assert!(result.iter().all(|light| light.read.is_some()));
+        assert!(result.iter().all(|light| light.conn.is_some()));
 
         Ok(())
     }
@@ -287,7 +566,7 @@ name: light_one\r\n";
         let fake_sender = UdpSocket::bind(client_addr)?;
 
         fake_sender.set_nonblocking(true)?;
-        let client = YeeClient { seeker: fake_sender, multicast_addr: fake_multicast_addr };
+        let client = client(fake_sender, fake_multicast_addr.into());
 
         // send mock messages
         let fake_addr_1 = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 56356);
@@ -324,6 +603,336 @@ name: light_one\r\n";
         Ok(())
     }
 
+    #[test]
+    fn report_rejected_responses() -> anyhow::Result<()> {
+        // given
+        let multicast_listener = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))?;
+        let seeker = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))?;
+        seeker.set_nonblocking(true)?;
+        let client_addr = seeker.local_addr()?;
+        let client = client(seeker, multicast_listener.local_addr()?);
+
+        let fake_light = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))?;
+        fake_light.send_to(&[0xff, 0x00, 0x13, 0x37], client_addr)?;
+        fake_light.send_to(b"HTTP/1.1 200 OK\r\nLocation: yeelight://127.0.0.1:1\r\nid: 0x1\r\n", client_addr)?;
+        // nothing listens on the location
+        let closed_addr = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))?.local_addr()?;
+        let msg = format!("HTTP/1.1 200 OK\r
+Location: yeelight://{}\r
+id: 0x12345abcde\r
+model: mono\r
+fw_ver: 20\r
+support: get_prop set_power\r
+power: on\r
+bright: 100\r
+color_mode: 2\r
+ct: 0\r
+rgb: 23\r
+hue: 34\r
+sat: 45\r
+name: light_one\r\n", closed_addr);
+        fake_light.send_to(msg.as_bytes(), client_addr)?;
+
+        // when
+        let report = client.get_report(Duration::from_millis(300));
+
+        // then
+        assert_eq!(report.lights.len(), 0);
+        assert_eq!(report.rejected.len(), 3);
+        assert!(report.rejected.iter().all(|r| r.sender == fake_light.local_addr().unwrap()));
+        assert!(matches!(report.rejected[0].error, YeeError::InvalidResponse { .. }));
+        assert!(matches!(report.rejected[1].error, YeeError::FieldNotFound { .. }));
+        assert!(matches!(report.rejected[2].error, YeeError::IoError { .. }));
+        assert!(report.send_errors.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn record_interface_of_lights() -> anyhow::Result<()> {
+        // given
+        let multicast_listener = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))?;
+        let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))?;
+        socket.set_nonblocking(true)?;
+        let client_addr = socket.local_addr()?;
+        let seeker = Seeker { socket, interface: Some(Ipv4Addr::LOCALHOST), netmask: None };
+        let multicast_addr = multicast_listener.local_addr()?;
+        let search_msg = search_msg(&multicast_addr, DEFAULT_SEARCH_TARGET);
+        let client = YeeClient { seekers: vec![seeker], multicast_addr, search_msg };
+
+        let fake_listener = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))?;
+        let fake_light = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))?;
+        fake_light.send_to(search_response(fake_listener.local_addr()?).as_bytes(), client_addr)?;
+
+        // when
+        let lights = client.get_response(Duration::from_millis(300));
+
+        // then
+        assert_eq!(lights.len(), 1);
+        assert_eq!(lights[0].interface(), Some(Ipv4Addr::LOCALHOST));
+        Ok(())
+    }
+
+    #[test]
+    fn interface_by_subnet_of_sender() -> anyhow::Result<()> {
+        // given
+        let lan = (Some(Ipv4Addr::new(192, 168, 1, 2)), Some(Ipv4Addr::new(255, 255, 255, 0)));
+        let iot = (Some(Ipv4Addr::new(10, 0, 20, 1)), Some(Ipv4Addr::new(255, 255, 0, 0)));
+        let unknown = Some(Ipv4Addr::new(172, 16, 0, 1));
+
+        // when
+        let on_iot = interface_of(vec![lan, iot], lan.0, &"10.0.30.7:1982".parse()?);
+        let elsewhere = interface_of(vec![lan, iot], unknown, &"172.16.0.9:1982".parse()?);
+
+        // then
+        assert_eq!(on_iot, iot.0);
+        assert_eq!(elsewhere, unknown);
+        Ok(())
+    }
+
+    #[test]
+    fn send_repeated_probes() -> anyhow::Result<()> {
+        // given
+        let multicast_listener = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))?;
+        multicast_listener.set_nonblocking(true)?;
+        let seeker = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))?;
+        seeker.set_nonblocking(true)?;
+        let client = client(seeker, multicast_listener.local_addr()?);
+        let options = SearchOptions::new(Duration::from_millis(400))
+            .probes(3, Duration::from_millis(50))
+            .jitter(Duration::from_millis(20));
+
+        // when
+        client.search(&options);
+
+        // then
+        let mut recv_buffer = [0; 512];
+        let mut probes = 0;
+        while let Ok((size, _)) = multicast_listener.recv_from(&mut recv_buffer) {
+            assert_eq!(&recv_buffer[..size], SEARCH_MSG.as_bytes());
+            probes += 1;
+        }
+        assert_eq!(probes, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn stop_once_found() -> anyhow::Result<()> {
+        // given
+        let multicast_listener = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))?;
+        let seeker = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))?;
+        seeker.set_nonblocking(true)?;
+        let client_addr = seeker.local_addr()?;
+        let client = client(seeker, multicast_listener.local_addr()?);
+        let options = SearchOptions::new(Duration::from_secs(10)).until_ids(vec!["0x12345abcde"]);
+
+        let fake_listener = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))?;
+        let fake_light = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))?;
+        fake_light.send_to(search_response(fake_listener.local_addr()?).as_bytes(), client_addr)?;
+
+        // when
+        let start = Instant::now();
+        let report = client.search(&options);
+
+        // then
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(report.lights.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn yield_lights_as_they_answer() -> anyhow::Result<()> {
+        // given
+        let multicast_listener = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))?;
+        let seeker = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))?;
+        seeker.set_nonblocking(true)?;
+        let client_addr = seeker.local_addr()?;
+        let client = client(seeker, multicast_listener.local_addr()?);
+
+        let fake_listener = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))?;
+        let fake_light = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))?;
+        let msg = search_response(fake_listener.local_addr()?);
+        fake_light.send_to(msg.as_bytes(), client_addr)?;
+        fake_light.send_to(msg.as_bytes(), client_addr)?;
+        fake_light.send_to(b"not a light", client_addr)?;
+
+        // when
+        let start = Instant::now();
+        let mut discovery = client.discover(&SearchOptions::new(Duration::from_secs(1)));
+        let first = discovery.next();
+        let first_after = start.elapsed();
+        let rest = discovery.next();
+
+        // then
+        assert_eq!(first.map(|light| light.id().to_string()), Some("0x12345abcde".to_string()));
+        assert!(first_after < Duration::from_millis(500));
+        assert!(rest.is_none());
+        assert_eq!(discovery.rejected().len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn deadline_holds_while_connecting() -> anyhow::Result<()> {
+        // given
+        let multicast_listener = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))?;
+        let seeker = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))?;
+        seeker.set_nonblocking(true)?;
+        let client_addr = seeker.local_addr()?;
+        let client = client(seeker, multicast_listener.local_addr()?);
+        // a listener that never accepts, once its backlog is full further connects hang
+        let full = socket2::Socket::new(socket2::Domain::IPV4, socket2::Type::STREAM, None)?;
+        full.bind(&SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0).into())?;
+        full.listen(0)?;
+        let full_addr = full.local_addr()?.as_socket().unwrap();
+        let _backlog: Vec<_> = (0..4)
+            .filter_map(|_| std::net::TcpStream::connect_timeout(&full_addr, Duration::from_millis(50)).ok())
+            .collect();
+        let fake_light = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))?;
+        fake_light.send_to(search_response(full_addr).as_bytes(), client_addr)?;
+
+        // when
+        let start = Instant::now();
+        let report = client.search(&SearchOptions::new(Duration::from_millis(300)));
+
+        // then
+        assert!(start.elapsed() < Duration::from_secs(1));
+        // still connecting when the search ended
+        assert!(report.lights.is_empty());
+        assert!(report.rejected.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn reject_unreachable_light_once() -> anyhow::Result<()> {
+        // given
+        let multicast_listener = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))?;
+        let seeker = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))?;
+        seeker.set_nonblocking(true)?;
+        let client_addr = seeker.local_addr()?;
+        let client = client(seeker, multicast_listener.local_addr()?);
+        let closed = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))?.local_addr()?;
+        let fake_light = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))?;
+        // answering every probe
+        for _ in 0..3 {
+            fake_light.send_to(search_response(closed).as_bytes(), client_addr)?;
+        }
+
+        // when
+        let report = client.search(&SearchOptions::new(Duration::from_millis(300)));
+
+        // then
+        assert!(report.lights.is_empty());
+        assert_eq!(report.rejected.len(), 1);
+        assert_eq!(report.rejected[0].sender, fake_light.local_addr()?);
+        Ok(())
+    }
+
+    #[test]
+    fn probe_single_light() -> anyhow::Result<()> {
+        // given
+        let client = client(UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))?, "127.0.0.1:1982".parse()?);
+        let fake_listener = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))?;
+        let fake_light = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))?;
+        let fake_addr = fake_light.local_addr()?;
+        let response = search_response(fake_listener.local_addr()?);
+        let handle = std::thread::spawn(move || -> std::io::Result<Vec<u8>> {
+            let mut buf = [0; 512];
+            let (size, sender) = fake_light.recv_from(&mut buf)?;
+            fake_light.send_to(response.as_bytes(), sender)?;
+            Ok(buf[..size].to_vec())
+        });
+
+        // when
+        let light = client.probe_at(fake_addr, Duration::from_secs(2))?;
+
+        // then
+        assert_eq!(handle.join().unwrap()?, SEARCH_MSG.as_bytes());
+        assert_eq!(light.id(), "0x12345abcde");
+        assert!(light.conn.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn probe_times_out() -> anyhow::Result<()> {
+        // given
+        let client = client(UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))?, "127.0.0.1:1982".parse()?);
+        let silent_light = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))?;
+
+        // when
+        let result = client.probe_at(silent_light.local_addr()?, Duration::from_millis(100));
+
+        // then
+        assert!(matches!(result, Err(YeeError::Timeout { operation: "probe" })));
+        Ok(())
+    }
+
+    #[test]
+    fn sweep_subnet() -> anyhow::Result<()> {
+        // given
+        let client = client(UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))?, "127.0.0.1:1982".parse()?);
+
+        // when
+        let report = client.sweep("127.0.0.0/29", Duration::from_millis(100), 4)?;
+
+        // then
+        assert!(report.lights.is_empty());
+        assert!(report.rejected.is_empty());
+        assert!(matches!(client.sweep("127.0.0.0/8", Duration::from_millis(100), 4),
+                         Err(YeeError::InvalidValue { field_name: "cidr", .. })));
+        Ok(())
+    }
+
+    /// Answers a single search on `addr` with a light at `location`.
+    fn fake_responder(addr: SocketAddr, location: SocketAddr) -> std::io::Result<()> {
+        let fake_light = UdpSocket::bind(addr)?;
+        std::thread::spawn(move || -> std::io::Result<()> {
+            let mut buf = [0; 512];
+            let (_, sender) = fake_light.recv_from(&mut buf)?;
+            fake_light.send_to(search_response(location).as_bytes(), sender)?;
+            Ok(())
+        });
+        Ok(())
+    }
+
+    #[test]
+    fn sweep_reports_unreachable_lights() -> anyhow::Result<()> {
+        // given
+        let client = client(UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))?, "127.0.0.1:1982".parse()?);
+        let fake_listener = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))?;
+        let closed = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))?.local_addr()?;
+        let reachable = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 2), 0))?.local_addr()?;
+        let unreachable = SocketAddr::new(Ipv4Addr::new(127, 0, 0, 3).into(), reachable.port());
+        fake_responder(reachable, fake_listener.local_addr()?)?;
+        fake_responder(unreachable, closed)?;
+
+        // when
+        let report = client.sweep_port("127.0.0.0/29", reachable.port(), Duration::from_millis(500), 4)?;
+
+        // then
+        assert_eq!(report.lights.len(), 1);
+        assert_eq!(report.lights[0].location(), &fake_listener.local_addr()?);
+        assert_eq!(report.rejected.len(), 1);
+        assert_eq!(report.rejected[0].sender, unreachable);
+        assert!(matches!(&report.rejected[0].error,
+                         YeeError::IoError { source } if source.kind() == std::io::ErrorKind::ConnectionRefused));
+        Ok(())
+    }
+
+    #[test]
+    fn report_send_errors() -> anyhow::Result<()> {
+        // given
+        let seeker = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))?;
+        seeker.set_nonblocking(true)?;
+        // an IPv4 socket can't send to an IPv6 group
+        let client = client(seeker, "[::1]:1982".parse()?);
+
+        // when
+        let report = client.get_report(Duration::from_millis(50));
+
+        // then
+        assert_eq!(report.send_errors.len(), 1);
+        Ok(())
+    }
+
     #[test]
     fn return_no_duplicate_lights() -> anyhow::Result<()> {
         // GIVEN
@@ -337,7 +946,7 @@ name: light_one\r\n";
         let fake_sender = UdpSocket::bind(client_addr)?;
 
         fake_sender.set_nonblocking(true)?;
-        let client = YeeClient { seeker: fake_sender, multicast_addr: fake_multicast_addr };
+        let client = client(fake_sender, fake_multicast_addr.into());
 
         // send mock messages
         let fake_addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 23395);
@@ -1,20 +1,34 @@
 use std::collections::{HashMap, HashSet};
 use std::hash::{Hash, Hasher};
-use std::io::{BufRead, BufReader, BufWriter, Write};
-use std::net::{SocketAddr, SocketAddrV4, TcpStream};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
 
 use lazy_static::*;
 use regex::Regex;
-use serde_json::json;
-
-use crate::err::YeeError;
-use crate::fields::{ColorMode, PowerStatus, Rgb};
-use crate::req::{Req, Transition};
-use std::net::Shutdown::Read;
+use serde_json::{json, Value};
+
+use crate::{DEFAULT_SEARCH_TARGET, MULTICAST_PORT, search_msg, unicast_search};
+use crate::background::{BackgroundLight, BackgroundState};
+use crate::conn::{Connection, Subscribers};
+use crate::err::{io_or_timeout, YeeError};
+use crate::limit::{LimitMode, QueuedFailure, QuotaUsage, RateLimit, TokenBucket};
+use crate::fields::{ActiveMode, ColorMode, PowerMode, PowerStatus, Rgb};
+use crate::flow::Flow;
+use crate::notify::PropertyChange;
+use crate::prop::{decode_name, encode_name, PropValue, Property};
+use crate::retry::RetryPolicy;
+use crate::req::{AdjustAction, AdjustProp, Command, Req, Transition, whole_minutes};
+use crate::scene::Scene;
 
 #[derive(Debug)]
 pub struct Light {
-    location: SocketAddrV4,
+    location: SocketAddr,
+    // the address of the local interface the light was found on, if the search was bound to one
+    pub(crate) interface: Option<Ipv4Addr>,
     id: String,
     model: String,
     fw_ver: u8,
@@ -36,15 +50,87 @@ pub struct Light {
 
     name: String,
 
+    // only reported by ceiling lights with a night light, after a refresh
+    active_mode: Option<ActiveMode>,
+    nl_br: Option<u8>,
+
+    // only used by models with a background light
+    pub(crate) bg: BackgroundState,
+    // cached properties that couldn't be re-read after an adjust
+    stale: HashSet<Property>,
+
+    subscribers: Subscribers,
+
+    connect_timeout: Option<Duration>,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    // set for the calls made in `with_deadline`
+    deadline: Option<Instant>,
+    retry: RetryPolicy,
+    limiter: Option<TokenBucket>,
+    // commands held back by `LimitMode::Coalesce`, in the order they were made, with the deadline they were made under
+    queued: Vec<(Command, Option<Instant>)>,
+    // queued commands that failed when a later call sent them
+    queue_failures: Vec<QueuedFailure>,
+
     // wrapped in option for late init
     // if successfully made a Light, can always assume it is valid
-    pub(crate) read: Option<BufReader<TcpStream>>,
-    pub(crate) write: Option<BufWriter<TcpStream>>,
+    pub(crate) conn: Option<Connection>,
+}
+
+/// Which of the lights in a device a command is for.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) enum Channel {
+    Main,
+    // the ambient light of dual-light models
+    Background,
+}
+
+impl Channel {
+    /// The method name on this channel, e.g. `bg_set_rgb` for `set_rgb`.
+    pub(crate) fn method(self, method_name: &'static str) -> &'static str {
+        match self {
+            Self::Main => method_name,
+            Self::Background => match method_name {
+                "set_ct_abx" => "bg_set_ct_abx",
+                "set_rgb" => "bg_set_rgb",
+                "set_bright" => "bg_set_bright",
+                "set_hsv" => "bg_set_hsv",
+                "set_power" => "bg_set_power",
+                "toggle" => "bg_toggle",
+                "start_cf" => "bg_start_cf",
+                "stop_cf" => "bg_stop_cf",
+                "set_scene" => "bg_set_scene",
+                "set_adjust" => "bg_set_adjust",
+                "adjust_bright" => "bg_adjust_bright",
+                "adjust_ct" => "bg_adjust_ct",
+                "adjust_color" => "bg_adjust_color",
+                _ => unreachable!("no background method for {}", method_name)
+            }
+        }
+    }
+
+    /// The property on this channel, e.g. `Property::BgRgb` for `Property::Rgb`.
+    pub(crate) fn prop(self, prop: Property) -> Property {
+        match self {
+            Self::Main => prop,
+            Self::Background => prop.background()
+                .unwrap_or_else(|| unreachable!("no background property for {}", prop))
+        }
+    }
 }
 
+// in bytes, longer names are cut off by the firmware
+const MAX_NAME_LEN: usize = 64;
+
+/// The default connect, read and write timeout of a `Light`.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// how long `Light::connect` waits for the discovery response, lights answer at once if at all
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+
 lazy_static! {
     static ref MATCH_IP: Regex = Regex::new(r#"yeelight://(.*)"#).unwrap();
-    static ref MATCH_ERR_MSG: Regex = Regex::new(r#""message":"(.*)""#).unwrap();
 }
 
 macro_rules! get_field {
@@ -81,7 +167,7 @@ impl Light {
         let model = get_field!(fields, "model")?.to_string();
         let fw_ver = get_field!(fields, "fw_ver", u8)?;
         let power = get_field!(fields, "power", PowerStatus, true)?;
-        let support: HashSet<String> = get_field!(fields, "support")?.trim()
+        let support: HashSet<String> = get_field!(fields, "support")?
             .split_whitespace()
             .map(|s| s.to_string())
             .collect();
@@ -91,10 +177,11 @@ impl Light {
         let rgb = get_field!(fields, "rgb", Rgb, true)?;
         let hue: u16 = get_field!(fields, "hue", u16)?;
         let sat = get_field!(fields, "sat", u8)?;
-        let name = get_field!(fields, "name")?.to_string();
+        let name = decode_name(get_field!(fields, "name")?);
 
         let location = get_field!(fields,"Location")?;
-        let captures = MATCH_IP
+        // IPv6 addresses are in brackets, e.g. yeelight://[fe80::1]:55443
+        let location = MATCH_IP
             .captures(location)
             .and_then(|c| c.get(1))
             .ok_or(YeeError::FieldNotFound { field_name: "Location" })
@@ -104,252 +191,2150 @@ impl Light {
                 .map_err(|_| YeeError::ParseFieldFailed { field_name: "Location", source: None })
             )
             ?;
-        let location = match captures {
-            SocketAddr::V4(v4) => v4,
-            _ => panic!("Light should not have an IPv6 address")
+
+        let mut light = Light::unknown(location);
+        light.id = id;
+        light.model = model;
+        light.fw_ver = fw_ver;
+        light.support = support;
+        light.power = power;
+        light.bright = bright;
+        light.color_mode = color_mode;
+        light.ct = ct;
+        light.rgb = rgb;
+        light.hue = hue;
+        light.sat = sat;
+        light.name = name;
+        Ok(light)
+    }
+
+    /// Connects to the light at the given address, e.g. one that multicast discovery can't reach.
+    ///
+    /// The light is asked for its discovery response directly, on the search port of the same IP,
+    /// which has its id, model, firmware version and supported methods, and the rest of the state is read with `get_prop`.
+    ///
+    /// If the light doesn't respond to that, the state is read with `get_prop`,
+    /// and the supported methods are worked out from the properties the light reports and whether it answers `cron_get`.
+    /// Then `id` is the address, `model` is empty and `fw_ver` is 0.
+    pub fn connect(addr: SocketAddr) -> Result<Light, YeeError> {
+        Self::connect_probing(addr, SocketAddr::new(addr.ip(), MULTICAST_PORT))
+    }
+
+    /// Like `connect`, asking `probe` for the discovery response.
+    pub(crate) fn connect_probing(addr: SocketAddr, probe: SocketAddr) -> Result<Light, YeeError> {
+        let msg = search_msg(&probe, DEFAULT_SEARCH_TARGET);
+        let mut light = match unicast_search(probe, &msg, PROBE_TIMEOUT) {
+            Ok(light) => light,
+            Err(_) => return Self::connect_unprobed(addr)
         };
+        // the address that was asked for, even if the light reports another one of its own
+        light.location = addr;
+        light.init()?;
+        if light.support.contains("get_prop") {
+            light.refresh()?;
+        }
+        Ok(light)
+    }
+
+    fn connect_unprobed(addr: SocketAddr) -> Result<Light, YeeError> {
+        let mut light = Light::unknown(addr);
+        light.id = addr.to_string();
+        light.init()?;
+
+        // get_prop isn't in `support` yet
+        let req = Req::new("get_prop".to_string(), CONNECT_PROPS.iter().map(|p| json!(p.name())).collect());
+        let values = decode_props(CONNECT_PROPS, light.send(req, true)?)?;
+        let mut support = supported_methods(&values);
+        // a read-only method, so it is safe to probe
+        match light.send(Req::new("cron_get".to_string(), vec![json!(0)]), true) {
+            Ok(_) => support.extend(["cron_add", "cron_get", "cron_del"].iter().map(|m| m.to_string())),
+            Err(YeeError::Device { .. }) => {}
+            Err(e) => return Err(e)
+        }
+        light.support = support;
+        light.apply_read(CONNECT_PROPS, values);
+        Ok(light)
+    }
+
+    /// A light at the given location that nothing is known about yet.
+    fn unknown(location: SocketAddr) -> Light {
+        Light {
+            location,
+            interface: None,
+            id: String::new(),
+            model: String::new(),
+            fw_ver: 0,
+            support: HashSet::new(),
+            power: PowerStatus::Off,
+            bright: 0,
+            color_mode: ColorMode::ColorTemperature,
+            ct: 0,
+            rgb: Rgb::empty(),
+            hue: 0,
+            sat: 0,
+            name: String::new(),
+            active_mode: None,
+            nl_br: None,
+            bg: BackgroundState::new(),
+            stale: HashSet::new(),
+            subscribers: Arc::new(Mutex::new(Vec::new())),
+            connect_timeout: Some(DEFAULT_TIMEOUT),
+            read_timeout: Some(DEFAULT_TIMEOUT),
+            write_timeout: Some(DEFAULT_TIMEOUT),
+            deadline: None,
+            retry: RetryPolicy::default(),
+            limiter: None,
+            queued: Vec::new(),
+            queue_failures: Vec::new(),
+            conn: None,
+        }
+    }
 
-        Ok(Light { location, id, model, fw_ver, power, support, bright, color_mode, ct, rgb, hue, sat, name, read: None, write: None })
+    /// Gives a link-local location the scope of the address its response came from,
+    /// since the location a light reports can't name the interface it is reachable on.
+    pub(crate) fn scope_to(&mut self, sender: &SocketAddr) {
+        if let (SocketAddr::V6(location), SocketAddr::V6(sender)) = (&mut self.location, sender) {
+            let link_local = location.ip().segments()[0] & 0xffc0 == 0xfe80;
+            if link_local && location.scope_id() == 0 {
+                location.set_scope_id(sender.scope_id());
+            }
+        }
     }
 
     pub(crate) fn init(&mut self) -> Result<(), YeeError> {
-        if self.read.is_some() {
+        if self.conn.is_some() {
             return Ok(());
         }
-        let connection = TcpStream::connect(self.location)?;
-        self.write = Some(BufWriter::new(connection.try_clone()?));
-        self.read = Some(BufReader::new(connection));
+        let addr = self.location;
+        // a deadline cuts the connect timeout short
+        let timeout = match (self.connect_timeout, self.remaining()) {
+            (_, Some(remaining)) if remaining == Duration::from_secs(0) =>
+                return Err(YeeError::Timeout { operation: "connect" }),
+            (Some(timeout), Some(remaining)) => Some(timeout.min(remaining)),
+            (timeout, remaining) => timeout.or(remaining)
+        };
+        let stream = match timeout {
+            Some(timeout) => TcpStream::connect_timeout(&addr, timeout),
+            None => TcpStream::connect(addr)
+        }.map_err(io_or_timeout("connect"))?;
+        stream.set_write_timeout(self.write_timeout)?;
+        self.conn = Some(Connection::new(stream, self.subscribers.clone())?);
+        Ok(())
+    }
+
+    /// Sets how long to wait when connecting to the light, or `None` to wait forever.
+    pub fn set_connect_timeout(&mut self, timeout: Option<Duration>) -> Result<(), YeeError> {
+        self.connect_timeout = check_timeout(timeout)?;
+        Ok(())
+    }
+
+    /// Sets how long to wait for the response to each request, or `None` to wait forever.
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) -> Result<(), YeeError> {
+        self.read_timeout = check_timeout(timeout)?;
+        Ok(())
+    }
+
+    /// Sets how long to wait for a request to be sent, or `None` to wait forever.
+    pub fn set_write_timeout(&mut self, timeout: Option<Duration>) -> Result<(), YeeError> {
+        self.write_timeout = check_timeout(timeout)?;
+        if let Some(conn) = &self.conn {
+            conn.set_write_timeout(self.write_timeout)?;
+        }
         Ok(())
     }
 
+    /// Sets how the light reconnects and which commands are retried after the connection broke.
+    pub fn set_retry_policy(&mut self, retry: RetryPolicy) {
+        self.retry = retry;
+    }
+
+    /// Sets the limit on the commands sent to the light, or `None` to send them as fast as they are made.
+    ///
+    /// There is no limit until one is set, `RateLimit::default()` is the device quota. The quota starts out unused. Commands queued by `LimitMode::Coalesce` are kept.
+    pub fn set_rate_limit(&mut self, limit: Option<RateLimit>) {
+        self.limiter = limit.map(TokenBucket::new);
+    }
+
+    /// Sends every command queued by `LimitMode::Coalesce`, waiting for the quota if needed.
+    ///
+    /// Commands still queued when the light is dropped are discarded, so call this before dropping it.
+    /// Returns the error of the first command that failed, the others are kept for `take_queue_failures`.
+    pub fn flush(&mut self) -> Result<(), YeeError> {
+        let mut failures = self.send_queued(true).into_iter();
+        let first = failures.next();
+        self.queue_failures.extend(failures);
+        first.map_or(Ok(()), |failure| Err(failure.error))
+    }
+
+    /// Takes the commands queued by `LimitMode::Coalesce` that failed once a later call on the light sent them.
+    ///
+    /// That call only returns its own error, so these are kept until taken.
+    pub fn take_queue_failures(&mut self) -> Vec<QueuedFailure> {
+        std::mem::take(&mut self.queue_failures)
+    }
+
+    /// Runs `f` with every request it makes on this light having to complete before `deadline`.
+    ///
+    /// e.g. `light.with_deadline(deadline, |l| l.set_bright(50, Transition::sudden()))`.
+    /// The connect and read timeouts still apply, cut short by the deadline, as are the waits between retries.
+    pub fn with_deadline<T, F>(&mut self, deadline: Instant, f: F) -> Result<T, YeeError>
+        where F: FnOnce(&mut Light) -> Result<T, YeeError> {
+        let outer = self.deadline;
+        // a nested deadline can't extend the outer one
+        self.deadline = Some(outer.map_or(deadline, |outer| outer.min(deadline)));
+        let result = f(self);
+        self.deadline = outer;
+        result
+    }
+
     pub fn set_ct_abx(&mut self, temperature: u16, transition: Transition) -> Result<(), YeeError> {
-        if !self.support.contains("set_ct_abx") {
-            return Err(YeeError::MethodNotSupported { method_name: "set_ct_abx" });
+        let cmd = self.set_ct_abx_cmd(Channel::Main, temperature, transition)?;
+        self.run(cmd)
+    }
+
+    pub fn set_rgb(&mut self, rgb: Rgb, transition: Transition) -> Result<(), YeeError> {
+        let cmd = self.set_rgb_cmd(Channel::Main, rgb, transition)?;
+        self.run(cmd)
+    }
+
+    pub fn set_bright(&mut self, brightness: u8, transition: Transition) -> Result<(), YeeError> {
+        let cmd = self.set_bright_cmd(Channel::Main, brightness, transition)?;
+        self.run(cmd)
+    }
+
+    pub fn set_hsv(&mut self, hue: u16, sat: u8, transition: Transition) -> Result<(), YeeError> {
+        let cmd = self.set_hsv_cmd(Channel::Main, hue, sat, transition)?;
+        self.run(cmd)
+    }
+
+    pub fn set_power(&mut self, power: PowerStatus, transition: Transition) -> Result<(), YeeError> {
+        let cmd = self.set_power_cmd(Channel::Main, power, transition, None)?;
+        self.run(cmd)
+    }
+
+    /// Like `set_power`, but when turning on, turns on into the given mode.
+    pub fn set_power_with_mode(&mut self, power: PowerStatus, transition: Transition, mode: PowerMode)
+                               -> Result<(), YeeError> {
+        let cmd = self.set_power_cmd(Channel::Main, power, transition, Some(mode))?;
+        self.run(cmd)
+    }
+
+    /// Turns a ceiling light on into its normal (daylight) or night light (moonlight) mode.
+    pub fn set_active_mode(&mut self, mode: ActiveMode, transition: Transition) -> Result<(), YeeError> {
+        let cmd = self.set_active_mode_cmd(mode, transition)?;
+        self.run(cmd)
+    }
+
+    pub fn toggle(&mut self) -> Result<(), YeeError> {
+        let cmd = self.toggle_cmd(Channel::Main)?;
+        self.run(cmd)
+    }
+
+    pub fn start_cf(&mut self, flow: &Flow) -> Result<(), YeeError> {
+        let cmd = self.start_cf_cmd(Channel::Main, flow)?;
+        self.run(cmd)
+    }
+
+    pub fn stop_cf(&mut self) -> Result<(), YeeError> {
+        let cmd = self.stop_cf_cmd(Channel::Main)?;
+        self.run(cmd)
+    }
+
+    /// Turns the light on directly into the given scene.
+    pub fn set_scene(&mut self, scene: &Scene) -> Result<(), YeeError> {
+        let cmd = self.set_scene_cmd(Channel::Main, scene)?;
+        self.run(cmd)
+    }
+
+    /// Adjusts a property without knowing its current value.
+    ///
+    /// The new value can't be predicted (e.g. `AdjustAction::Circle` wraps around),
+    /// so the affected fields are re-read from the light if it supports `get_prop` and the quota has a command to spare.
+    /// Otherwise, or if reading them fails, they are listed in `stale` until they are read again.
+    pub fn adjust(&mut self, action: AdjustAction, prop: AdjustProp) -> Result<(), YeeError> {
+        let cmd = self.adjust_cmd(Channel::Main, action, prop)?;
+        self.run(cmd)?;
+        self.resync(adjusted_props(prop));
+        Ok(())
+    }
+
+    /// Adjusts the brightness by a percentage from -100 to 100.
+    pub fn adjust_bright(&mut self, percentage: i8, duration: Duration) -> Result<(), YeeError> {
+        let cmd = self.adjust_by_cmd(Channel::Main, AdjustProp::Bright, percentage, duration)?;
+        self.run(cmd)?;
+        self.resync(adjusted_props(AdjustProp::Bright));
+        Ok(())
+    }
+
+    /// Adjusts the color temperature by a percentage from -100 to 100.
+    pub fn adjust_ct(&mut self, percentage: i8, duration: Duration) -> Result<(), YeeError> {
+        let cmd = self.adjust_by_cmd(Channel::Main, AdjustProp::Ct, percentage, duration)?;
+        self.run(cmd)?;
+        self.resync(adjusted_props(AdjustProp::Ct));
+        Ok(())
+    }
+
+    /// Adjusts the color by a percentage from -100 to 100.
+    pub fn adjust_color(&mut self, percentage: i8, duration: Duration) -> Result<(), YeeError> {
+        let cmd = self.adjust_by_cmd(Channel::Main, AdjustProp::Color, percentage, duration)?;
+        self.run(cmd)?;
+        self.resync(adjusted_props(AdjustProp::Color));
+        Ok(())
+    }
+
+    /// Stores the name on the light itself.
+    ///
+    /// A name with characters that aren't ASCII is stored base64-encoded, as the spec suggests,
+    /// and decoded again when read back. The firmware keeps at most 64 bytes of the stored name,
+    /// and the name can't be empty or have control characters, since it is sent back in discovery headers.
+    pub fn set_name(&mut self, name: &str) -> Result<(), YeeError> {
+        let cmd = self.set_name_cmd(name)?;
+        self.run(cmd)
+    }
+
+    /// Saves the current state as the state the light powers on into.
+    pub fn save_as_default(&mut self) -> Result<(), YeeError> {
+        let cmd = self.set_default_cmd()?;
+        self.run(cmd)
+    }
+
+    /// Returns a handle to the background light of dual-light models.
+    ///
+    /// Discovery responses only describe the main light, so the first call reads the background state
+    /// with `get_prop` if the light supports it. Until then `BackgroundLight::known` is false.
+    pub fn background(&mut self) -> Result<BackgroundLight<'_>, YeeError> {
+        self.check_support("bg_set_power")?;
+        if !self.bg.known && self.support.contains("get_prop") {
+            let values = self.get_prop(BG_PROPS)?;
+            self.apply_read(BG_PROPS, values);
+        }
+        Ok(BackgroundLight::new(self))
+    }
+
+    /// Toggles the main and background lights at the same time.
+    pub fn toggle_both(&mut self) -> Result<(), YeeError> {
+        let cmd = self.toggle_both_cmd()?;
+        self.run(cmd)
+    }
+
+    pub(crate) fn check_support(&self, method_name: &'static str) -> Result<(), YeeError> {
+        if self.support.contains(method_name) {
+            Ok(())
+        } else {
+            Err(YeeError::MethodNotSupported { method_name })
         }
+    }
+
+    pub(crate) fn set_ct_abx_cmd(&self, channel: Channel, temperature: u16, transition: Transition) -> Result<Command, YeeError> {
+        let method_name = channel.method("set_ct_abx");
+        self.check_support(method_name)?;
         // SPEC IS WRONG: temperature bounds should be 2700-6500
         if !(2700..=6500).contains(&temperature) {
             return Err(YeeError::InvalidValue { field_name: "ct", value: temperature.to_string() });
         }
-        let req = Req::new("set_ct_abx".to_string(),
+        let req = Req::new(method_name.to_string(),
                            vec![json!(temperature), json!(transition.text()), json!(transition.value())]);
-        self.send_req(&req)?;
-        self.ct = temperature;
-        Ok(())
+        Ok(Command::new(req, vec![(channel.prop(Property::Ct), PropValue::Ct(temperature))]).idempotent())
     }
 
-    pub fn set_rgb(&mut self, rgb: Rgb, transition: Transition) -> Result<(), YeeError> {
-        if !self.support.contains("set_rgb") {
-            return Err(YeeError::MethodNotSupported { method_name: "set_rgb" });
-        }
-        let req = Req::new("set_rgb".to_string(),
+    pub(crate) fn set_rgb_cmd(&self, channel: Channel, rgb: Rgb, transition: Transition) -> Result<Command, YeeError> {
+        let method_name = channel.method("set_rgb");
+        self.check_support(method_name)?;
+        let req = Req::new(method_name.to_string(),
                            vec![json!(rgb.get_num()), json!(transition.text()), json!(transition.value())]);
-        self.send_req(&req)?;
-        self.rgb = rgb;
-        Ok(())
+        Ok(Command::new(req, vec![(channel.prop(Property::Rgb), PropValue::Rgb(rgb))]).idempotent())
     }
 
-    pub fn set_bright(&mut self, brightness: u8, transition: Transition) -> Result<(), YeeError> {
-        if !self.support.contains("set_bright") {
-            return Err(YeeError::MethodNotSupported { method_name: "set_bright" });
-        }
+    pub(crate) fn set_bright_cmd(&self, channel: Channel, brightness: u8, transition: Transition) -> Result<Command, YeeError> {
+        let method_name = channel.method("set_bright");
+        self.check_support(method_name)?;
         if !(1..=100).contains(&brightness) {
             return Err(YeeError::InvalidValue { field_name: "bright", value: brightness.to_string() });
         }
-        let req = Req::new("set_bright".to_string(),
+        let req = Req::new(method_name.to_string(),
                            vec![json!(brightness), json!(transition.text()), json!(transition.value())]);
-        self.send_req(&req)?;
-        self.bright = brightness;
-        Ok(())
+        Ok(Command::new(req, vec![(channel.prop(Property::Bright), PropValue::Bright(brightness))]).idempotent())
     }
 
-    pub fn set_hsv(&mut self, hue: u16, sat: u8, transition: Transition) -> Result<(), YeeError> {
-        if !self.support.contains("set_hsv") {
-            return Err(YeeError::MethodNotSupported { method_name: "set_hsv" });
-        }
+    pub(crate) fn set_hsv_cmd(&self, channel: Channel, hue: u16, sat: u8, transition: Transition) -> Result<Command, YeeError> {
+        let method_name = channel.method("set_hsv");
+        self.check_support(method_name)?;
         if !(0..=359).contains(&hue) {
             return Err(YeeError::InvalidValue { field_name: "hue", value: hue.to_string() });
         } else if !(0..=100).contains(&sat) {
             return Err(YeeError::InvalidValue { field_name: "sat", value: sat.to_string() });
         }
-        let req = Req::new("set_hsv".to_string(),
+        let req = Req::new(method_name.to_string(),
                            vec![json!(hue), json!(sat), json!(transition.text()), json!(transition.value())]);
-        self.send_req(&req)?;
-        self.hue = hue;
-        self.sat = sat;
-        Ok(())
+        Ok(Command::new(req, vec![(channel.prop(Property::Hue), PropValue::Hue(hue)),
+                                  (channel.prop(Property::Sat), PropValue::Sat(sat))]).idempotent())
     }
 
-    pub fn set_power(&mut self, power: PowerStatus, transition: Transition) -> Result<(), YeeError> {
-        if !self.support.contains("set_power") {
-            return Err(YeeError::MethodNotSupported { method_name: "set_power" });
+    pub(crate) fn set_power_cmd(&self, channel: Channel, power: PowerStatus, transition: Transition,
+                                mode: Option<PowerMode>) -> Result<Command, YeeError> {
+        let method_name = channel.method("set_power");
+        self.check_support(method_name)?;
+        let mut params = vec![json!(power.to_string()), json!(transition.text()), json!(transition.value())];
+        let mut changes = vec![(channel.prop(Property::Power), PropValue::Power(power))];
+        if let Some(mode) = mode {
+            params.push(json!(mode.value()));
+            if power == PowerStatus::On && channel == Channel::Main {
+                changes.extend(self.power_mode_changes(mode));
+            }
         }
-        let req = Req::new("set_power".to_string(),
-                           vec![json!(power.to_string()), json!(transition.text()), json!(transition.value())]);
-        self.send_req(&req)?;
-        self.power = power;
-        Ok(())
+        Ok(Command::new(Req::new(method_name.to_string(), params), changes).idempotent())
     }
 
-    pub fn toggle(&mut self) -> Result<(), YeeError> {
-        if !self.support.contains("toggle") {
-            return Err(YeeError::MethodNotSupported { method_name: "toggle" });
-        }
-        let req = Req::new("toggle".to_string(), vec![]);
-        self.send_req(&req)?;
-        self.power = self.power.flip();
-        Ok(())
-    }
-
-    pub(crate) fn send_req(&mut self, req: &Req) -> Result<(), YeeError> {
-        let rand_val = req.id.to_string();
-        let mut json = serde_json::to_string(req).unwrap();
-        let reader = self.read.as_mut().unwrap();
-        let writer = self.write.as_mut().unwrap();
-        json.push_str("\r\n");
-        writer.write_all(json.as_bytes())?;
-        writer.flush()?;
-
-        let mut buf = String::new();
-        let rand_val = rand_val.to_string();
-        while !buf.contains(rand_val.as_str()) {
-            reader.read_line(&mut buf)?;
-        }
-        println!("{}", buf);
-        if buf.contains("error") {
-            let s =
-                MATCH_ERR_MSG.captures(&buf)
-                    .and_then(|c| c.get(0))
-                    .map(|s| s.as_str().to_string())
-                    .unwrap_or_else(String::new);
-            Err(YeeError::ChangeFailed { message: s })
-        } else {
-            Ok(())
+    pub(crate) fn set_active_mode_cmd(&self, mode: ActiveMode, transition: Transition) -> Result<Command, YeeError> {
+        let power_mode = match mode {
+            ActiveMode::Daylight => PowerMode::ColorTemperature,
+            ActiveMode::Moonlight => PowerMode::NightLight
+        };
+        self.set_power_cmd(Channel::Main, PowerStatus::On, transition, Some(power_mode))
+    }
+
+    /// The cached fields that change when turning on into the given mode.
+    fn power_mode_changes(&self, mode: PowerMode) -> Vec<(Property, PropValue)> {
+        let color_mode = match mode {
+            PowerMode::ColorTemperature => Some(ColorMode::ColorTemperature),
+            PowerMode::Color => Some(ColorMode::Color),
+            PowerMode::Hsv => Some(ColorMode::Hsv),
+            _ => None
+        };
+        let mut changes: Vec<_> = color_mode.into_iter()
+            .map(|m| (Property::ColorMode, PropValue::ColorMode(m)))
+            .collect();
+        match mode {
+            PowerMode::NightLight => changes.push((Property::ActiveMode, PropValue::ActiveMode(ActiveMode::Moonlight))),
+            // only lights that reported an active mode have a night light to leave
+            _ if color_mode.is_some() && self.active_mode.is_some() =>
+                changes.push((Property::ActiveMode, PropValue::ActiveMode(ActiveMode::Daylight))),
+            _ => {}
         }
+        changes
     }
 
-    pub fn location(&self) -> &SocketAddrV4 {
-        &self.location
+    pub(crate) fn toggle_cmd(&self, channel: Channel) -> Result<Command, YeeError> {
+        let method_name = channel.method("toggle");
+        self.check_support(method_name)?;
+        let req = Req::new(method_name.to_string(), vec![]);
+        let power = match channel {
+            Channel::Main => self.power,
+            Channel::Background => self.bg.power
+        };
+        Ok(Command::new(req, vec![(channel.prop(Property::Power), PropValue::Power(power.flip()))]))
     }
 
-    pub fn id(&self) -> &str {
-        &self.id
+    pub(crate) fn start_cf_cmd(&self, channel: Channel, flow: &Flow) -> Result<Command, YeeError> {
+        let method_name = channel.method("start_cf");
+        self.check_support(method_name)?;
+        let req = Req::new(method_name.to_string(),
+                           vec![json!(flow.count()), json!(flow.action().value()), json!(flow.expression())]);
+        Ok(Command::new(req, vec![]))
     }
 
-    pub fn model(&self) -> &str {
-        &self.model
+    pub(crate) fn stop_cf_cmd(&self, channel: Channel) -> Result<Command, YeeError> {
+        let method_name = channel.method("stop_cf");
+        self.check_support(method_name)?;
+        let req = Req::new(method_name.to_string(), vec![]);
+        Ok(Command::new(req, vec![]).idempotent())
     }
 
-    pub fn fw_ver(&self) -> u8 {
-        self.fw_ver
+    pub(crate) fn set_scene_cmd(&self, channel: Channel, scene: &Scene) -> Result<Command, YeeError> {
+        let method_name = channel.method("set_scene");
+        self.check_support(method_name)?;
+        let req = Req::new(method_name.to_string(), scene.params()?);
+        let mut changes = vec![(Property::Power, PropValue::Power(PowerStatus::On))];
+        match *scene {
+            Scene::Color { rgb, bright } => changes.extend(vec![
+                (Property::ColorMode, PropValue::ColorMode(ColorMode::Color)),
+                (Property::Rgb, PropValue::Rgb(rgb)),
+                (Property::Bright, PropValue::Bright(bright))]),
+            Scene::Hsv { hue, sat, bright } => changes.extend(vec![
+                (Property::ColorMode, PropValue::ColorMode(ColorMode::Hsv)),
+                (Property::Hue, PropValue::Hue(hue)),
+                (Property::Sat, PropValue::Sat(sat)),
+                (Property::Bright, PropValue::Bright(bright))]),
+            Scene::Ct { ct, bright } => changes.extend(vec![
+                (Property::ColorMode, PropValue::ColorMode(ColorMode::ColorTemperature)),
+                (Property::Ct, PropValue::Ct(ct)),
+                (Property::Bright, PropValue::Bright(bright))]),
+            Scene::AutoDelayOff { bright, .. } => changes.push((Property::Bright, PropValue::Bright(bright))),
+            Scene::Cf(_) => {}
+        }
+        let changes = changes.into_iter().map(|(prop, value)| (channel.prop(prop), value)).collect();
+        Ok(Command::new(req, changes).idempotent())
     }
 
-    pub fn support(&self) -> &HashSet<String> {
-        &self.support
+    pub(crate) fn adjust_cmd(&self, channel: Channel, action: AdjustAction, prop: AdjustProp) -> Result<Command, YeeError> {
+        let method_name = channel.method("set_adjust");
+        self.check_support(method_name)?;
+        if prop == AdjustProp::Color && action != AdjustAction::Circle {
+            return Err(YeeError::InvalidValue { field_name: "action", value: action.text().to_string() });
+        }
+        let req = Req::new(method_name.to_string(), vec![json!(action.text()), json!(prop.text())]);
+        Ok(Command::new(req, vec![]))
     }
 
-    pub fn power(&self) -> &PowerStatus {
-        &self.power
+    pub(crate) fn adjust_by_cmd(&self, channel: Channel, prop: AdjustProp, percentage: i8, duration: Duration) -> Result<Command, YeeError> {
+        let method_name = channel.method(match prop {
+            AdjustProp::Bright => "adjust_bright",
+            AdjustProp::Ct => "adjust_ct",
+            AdjustProp::Color => "adjust_color"
+        });
+        self.check_support(method_name)?;
+        if !(-100..=100).contains(&percentage) {
+            return Err(YeeError::InvalidValue { field_name: "percentage", value: percentage.to_string() });
+        }
+        let duration = Transition::smooth(duration)
+            .ok_or_else(|| YeeError::InvalidValue { field_name: "duration", value: format!("{:?}", duration) })?;
+        let req = Req::new(method_name.to_string(), vec![json!(percentage), json!(duration.value())]);
+        Ok(Command::new(req, vec![]))
     }
 
-    pub fn bright(&self) -> u8 {
-        self.bright
+    pub(crate) fn set_name_cmd(&self, name: &str) -> Result<Command, YeeError> {
+        self.check_support("set_name")?;
+        let stored = encode_name(name);
+        if name.is_empty() || stored.len() > MAX_NAME_LEN || name.chars().any(char::is_control) {
+            return Err(YeeError::InvalidValue { field_name: "name", value: name.to_string() });
+        }
+        let req = Req::new("set_name".to_string(), vec![json!(stored)]);
+        Ok(Command::new(req, vec![(Property::Name, PropValue::Name(name.to_string()))]).idempotent())
     }
 
-    pub fn color_mode(&self) -> &ColorMode {
-        &self.color_mode
+    pub(crate) fn set_default_cmd(&self) -> Result<Command, YeeError> {
+        self.check_support("set_default")?;
+        let req = Req::new("set_default".to_string(), vec![]);
+        Ok(Command::new(req, vec![]).idempotent())
     }
 
-    pub fn ct(&self) -> u16 {
-        self.ct
+    pub(crate) fn toggle_both_cmd(&self) -> Result<Command, YeeError> {
+        self.check_support("dev_toggle")?;
+        let req = Req::new("dev_toggle".to_string(), vec![]);
+        let changes = vec![(Property::Power, PropValue::Power(self.power.flip())),
+                           (Property::BgPower, PropValue::Power(self.bg.power.flip()))];
+        Ok(Command::new(req, changes))
     }
 
-    pub fn rgb(&self) -> &Rgb {
-        &self.rgb
+    /// Sends the command and applies its changes to the cached fields.
+    ///
+    /// With `LimitMode::Coalesce`, idempotent commands are queued instead, replacing the queued one with the same method,
+    /// and their changes are applied once they are sent.
+    pub(crate) fn run(&mut self, cmd: Command) -> Result<(), YeeError> {
+        let coalescing = self.limiter.as_ref().is_some_and(|l| l.limit().mode() == LimitMode::Coalesce);
+        if coalescing && cmd.idempotent {
+            let method = cmd.req.method.clone();
+            self.queued.retain(|(queued, _)| queued.req.method != method);
+            self.queued.push((cmd, self.deadline));
+            // only one command per method is queued, so a failure with this method is the command of this call
+            let mut own = None;
+            for failure in self.send_queued(false) {
+                if failure.method == method {
+                    own = Some(failure.error);
+                } else {
+                    self.queue_failures.push(failure);
+                }
+            }
+            return own.map_or(Ok(()), Err);
+        }
+        self.send_queued_first();
+        self.send_cmd(cmd)
     }
 
-    pub fn hue(&self) -> u16 {
-        self.hue
+    fn send_cmd(&mut self, cmd: Command) -> Result<(), YeeError> {
+        let result = self.send(cmd.req, cmd.idempotent)?;
+        expect_ok(result)?;
+        self.apply_all(cmd.changes);
+        Ok(())
     }
 
-    pub fn sat(&self) -> u8 {
-        self.sat
+    /// Sends the queued commands before a request that isn't queued, which keeps the requests in order,
+    /// e.g. a `get_prop` after a queued `set_bright` reads the new brightness.
+    fn send_queued_first(&mut self) {
+        if !self.queued.is_empty() {
+            let failures = self.send_queued(true);
+            self.queue_failures.extend(failures);
+        }
     }
 
-    pub fn name(&self) -> &str {
-        &self.name
+    /// Sends the queued commands, stopping once the quota is used up unless `wait` is set, and returns the failed ones.
+    ///
+    /// Each is sent within the deadline it was queued under, as well as the current one.
+    fn send_queued(&mut self, wait: bool) -> Vec<QueuedFailure> {
+        let mut failures = Vec::new();
+        while !self.queued.is_empty() {
+            if !wait && !self.limiter.as_ref().is_none_or(TokenBucket::has_token) {
+                break;
+            }
+            let (cmd, deadline) = self.queued.remove(0);
+            let method = cmd.req.method.clone();
+            let outer = self.deadline;
+            self.deadline = match (outer, deadline) {
+                (Some(outer), Some(deadline)) => Some(outer.min(deadline)),
+                (outer, deadline) => outer.or(deadline)
+            };
+            let result = self.send_cmd(cmd);
+            self.deadline = outer;
+            if let Err(error) = result {
+                failures.push(QueuedFailure { method, error });
+            }
+        }
+        failures
     }
-}
 
-impl Hash for Light {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        state.write(self.id.as_bytes());
+    /// Takes a command from the quota, waiting for it unless the limit is `LimitMode::Error`.
+    fn take_quota(&mut self) -> Result<(), YeeError> {
+        let limiter = match &mut self.limiter {
+            Some(limiter) => limiter,
+            None => return Ok(())
+        };
+        while let Err(retry_after) = limiter.try_take() {
+            if limiter.limit().mode() == LimitMode::Error {
+                return Err(YeeError::RateLimited { retry_after });
+            }
+            if self.deadline.is_some_and(|deadline| Instant::now() + retry_after > deadline) {
+                return Err(YeeError::Timeout { operation: "send within the command quota" });
+            }
+            sleep(retry_after);
+        }
+        Ok(())
     }
-}
 
-impl PartialEq for Light {
-    fn eq(&self, other: &Self) -> bool {
-        self.id.eq(&other.id)
+    /// Turns the light off after the given delay, rounded to whole minutes.
+    pub fn set_sleep_timer(&mut self, delay: Duration) -> Result<(), YeeError> {
+        let req = self.set_sleep_timer_req(delay)?;
+        self.send_req(req)?;
+        Ok(())
     }
-}
 
-impl Eq for Light {}
+    /// Returns the time left on the sleep timer in whole minutes, or `None` if no timer is set.
+    pub fn sleep_timer(&mut self) -> Result<Option<Duration>, YeeError> {
+        let req = self.sleep_timer_req()?;
+        self.send_queued_first();
+        let result = self.send(req, true)?;
+        decode_sleep_timer(result)
+    }
 
-#[cfg(test)]
-mod tests {
-    use std::collections::HashMap;
-    use std::net::{IpAddr, Ipv4Addr, SocketAddrV4, TcpListener};
+    pub fn cancel_sleep_timer(&mut self) -> Result<(), YeeError> {
+        let req = self.cancel_sleep_timer_req()?;
+        self.send_req(req)?;
+        Ok(())
+    }
 
-    use super::*;
+    pub(crate) fn set_sleep_timer_req(&self, delay: Duration) -> Result<Req, YeeError> {
+        self.check_support("cron_add")?;
+        let minutes = whole_minutes(delay)?;
+        // type 0 is the only cron job type: power off
+        Ok(Req::new("cron_add".to_string(), vec![json!(0), json!(minutes)]))
+    }
 
-    macro_rules! map {
-        ($($key:expr => $value: expr), *) => {{
-            let mut map = HashMap::new();
-            $(map.insert($key,$value);)*
-            map
-        }};
+    pub(crate) fn sleep_timer_req(&self) -> Result<Req, YeeError> {
+        self.check_support("cron_get")?;
+        Ok(Req::new("cron_get".to_string(), vec![json!(0)]))
     }
 
-    pub(crate) fn get_map() -> HashMap<&'static str, &'static str> {
-        let mut m: HashMap<&str, &str> =
-            map!(
-            "id" => "0x1234",
-            "model" => "floor",
-            "fw_ver" => "40", // can fail
-            "power" => "on", // can fail
-            "bright" => "34", // can fail
-            "color_mode" => "2", // can fail
-            "ct" => "0", // can fail
-            "rgb" => "657930", // 0A0A0A, can fail
-            "hue" => "314", // can fail
-            "sat" => "12", // can fail
-            "name" => "room_light",
-            "Location" => "yeelight://127.0.0.1:13454"
-            );
-        let support = "get_power set_power get_rgb set_rgb";
-        m.insert("support", support);
-        m
+    pub(crate) fn cancel_sleep_timer_req(&self) -> Result<Req, YeeError> {
+        self.check_support("cron_del")?;
+        Ok(Req::new("cron_del".to_string(), vec![json!(0), json!(0)]))
     }
 
-    #[test]
-    fn send_correct_req() -> anyhow::Result<()> {
+    /// Reads the given properties from the light.
+    ///
+    /// Properties the light reports as empty (i.e. unsupported by the model) are left out of the result.
+    pub fn get_prop(&mut self, props: &[Property]) -> Result<HashMap<Property, PropValue>, YeeError> {
+        let req = self.get_prop_req(props)?;
+        self.send_queued_first();
+        let values = self.send(req, true)?;
+        decode_props(props, values)
+    }
+
+    pub(crate) fn get_prop_req(&self, props: &[Property]) -> Result<Req, YeeError> {
+        self.check_support("get_prop")?;
+        Ok(Req::new("get_prop".to_string(), props.iter().map(|p| json!(p.name())).collect()))
+    }
+
+    /// Re-reads every cached field from the light.
+    pub fn refresh(&mut self) -> Result<(), YeeError> {
+        let props = self.refresh_props();
+        let values = self.get_prop(&props)?;
+        self.apply_read(&props, values);
         Ok(())
     }
 
-    #[test]
-    fn get_correct_location() -> anyhow::Result<()> {
-        // given
-        let map = get_map();
-        let expected_addr = match SocketAddr::new(IpAddr::from(Ipv4Addr::LOCALHOST), 13454) {
-            SocketAddr::V4(v4) => v4,
-            _ => unreachable!()
+    /// Every cached property this light has.
+    pub(crate) fn refresh_props(&self) -> Vec<Property> {
+        let mut props = vec![Property::Power, Property::Bright, Property::ColorMode, Property::Ct,
+                             Property::Rgb, Property::Hue, Property::Sat, Property::Name,
+                             Property::ActiveMode, Property::NlBr];
+        if self.support.contains("bg_set_power") {
+            props.extend_from_slice(BG_PROPS);
+        }
+        props
+    }
+
+    /// Re-reads the given properties if the light supports it without waiting for the quota,
+    /// otherwise marks them as stale.
+    pub(crate) fn resync(&mut self, props: &[Property]) {
+        let spare = self.limiter.as_ref().is_none_or(TokenBucket::has_token);
+        if !spare || !self.support.contains("get_prop") {
+            return self.mark_stale(props);
+        }
+        match self.get_prop(props) {
+            Ok(values) => self.apply_read(props, values),
+            Err(_) => self.mark_stale(props)
+        }
+    }
+
+    pub(crate) fn mark_stale(&mut self, props: &[Property]) {
+        self.stale.extend(props.iter().copied());
+    }
+
+    /// Applies the values read for `props`, the ones left out are unsupported so they aren't stale either.
+    pub(crate) fn apply_read(&mut self, props: &[Property], values: HashMap<Property, PropValue>) {
+        for prop in props {
+            self.stale.remove(prop);
+        }
+        if props.contains(&Property::BgPower) {
+            self.bg.known = true;
+        }
+        self.apply_all(values);
+    }
+
+    pub(crate) fn apply_all<I: IntoIterator<Item=(Property, PropValue)>>(&mut self, changes: I) {
+        for (prop, value) in changes {
+            self.apply(prop, value);
+        }
+    }
+
+    pub(crate) fn apply(&mut self, prop: Property, value: PropValue) {
+        self.stale.remove(&prop);
+        match (prop, value) {
+            (Property::Power, PropValue::Power(power)) => self.power = power,
+            (Property::Bright, PropValue::Bright(bright)) => self.bright = bright,
+            (Property::ColorMode, PropValue::ColorMode(mode)) => self.color_mode = mode,
+            (Property::Ct, PropValue::Ct(ct)) => self.ct = ct,
+            (Property::Rgb, PropValue::Rgb(rgb)) => self.rgb = rgb,
+            (Property::Hue, PropValue::Hue(hue)) => self.hue = hue,
+            (Property::Sat, PropValue::Sat(sat)) => self.sat = sat,
+            (Property::Name, PropValue::Name(name)) => self.name = name,
+            (Property::ActiveMode, PropValue::ActiveMode(mode)) => self.active_mode = Some(mode),
+            (Property::NlBr, PropValue::Bright(bright)) => self.nl_br = Some(bright),
+            (Property::BgPower, PropValue::Power(power)) => self.bg.power = power,
+            (Property::BgBright, PropValue::Bright(bright)) => self.bg.bright = bright,
+            (Property::BgColorMode, PropValue::ColorMode(mode)) => self.bg.color_mode = mode,
+            (Property::BgCt, PropValue::Ct(ct)) => self.bg.ct = ct,
+            (Property::BgRgb, PropValue::Rgb(rgb)) => self.bg.rgb = rgb,
+            (Property::BgHue, PropValue::Hue(hue)) => self.bg.hue = hue,
+            (Property::BgSat, PropValue::Sat(sat)) => self.bg.sat = sat,
+            // not cached
+            _ => {}
+        }
+    }
+
+    /// Calls any method on the light, returning its result.
+    ///
+    /// Unlike the other methods, this does not check `support` or update the cached fields.
+    pub fn call(&mut self, method: &str, params: Vec<Value>) -> Result<Vec<Value>, YeeError> {
+        let req = Req::new(method.to_string(), params);
+        self.send_req(req)
+    }
+
+    /// Sends every request before waiting for any response, returning the results in the same order.
+    ///
+    /// Like `call`, this does not check `support` or update the cached fields.
+    pub fn call_all(&mut self, reqs: Vec<Req>) -> Vec<Result<Vec<Value>, YeeError>> {
+        let timeout = match self.response_timeout() {
+            Ok(timeout) => timeout,
+            Err(_) => return reqs.iter().map(|_| Err(YeeError::Timeout { operation: "receive a response" })).collect()
         };
+        self.send_queued_first();
+        let mut pending = Vec::with_capacity(reqs.len());
+        for req in reqs {
+            pending.push(self.take_quota().and_then(|_| self.connection()?.send(req)));
+        }
+        // the requests are pipelined, so they share the timeout
+        let end = timeout.map(|t| Instant::now() + t);
+        let results = pending.into_iter()
+            .map(|p| p.and_then(|p| p.wait(end.map(|end| end.saturating_duration_since(Instant::now())))))
+            .collect();
+        self.apply_notifications();
+        results
+    }
 
-        // when
-        let light = Light::from_fields(&map)?;
+    /// Returns a channel that receives every change the light reports on its own, as soon as it arrives.
+    ///
+    /// The changes are applied to the cached fields on the next call on this light or in `poll_notifications`.
+    pub fn subscribe(&mut self) -> Receiver<PropertyChange> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
 
-        // then
-        assert_eq!(*light.location(), expected_addr);
-        Ok(())
+    /// Waits up to `timeout` for notifications and applies them, returning how many property changes were applied.
+    ///
+    /// This includes the changes that arrived after the response to the last call, which that call left for later.
+    pub fn poll_notifications(&mut self, timeout: Duration) -> Result<usize, YeeError> {
+        let start = Instant::now();
+        let mut count = 0;
+        loop {
+            let remaining = match timeout.checked_sub(start.elapsed()) {
+                Some(remaining) => remaining,
+                None => return Ok(count)
+            };
+            let change = self.connection()?.changes.recv_timeout(remaining);
+            match change {
+                Ok(change) => {
+                    self.apply(change.property, change.value);
+                    count += 1;
+                }
+                Err(RecvTimeoutError::Timeout) => return Ok(count),
+                Err(RecvTimeoutError::Disconnected) =>
+                    return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into())
+            }
+        }
+    }
+
+    /// Sends the request and returns the result the light responded with.
+    pub(crate) fn send_req(&mut self, req: Req) -> Result<Vec<Value>, YeeError> {
+        self.send_queued_first();
+        self.send(req, false)
+    }
+
+    /// Sends the request, retrying when connecting fails, and if it is idempotent,
+    /// when the connection broke or the response timed out.
+    ///
+    /// Reconnecting and sending again share the retries of the `RetryPolicy`.
+    fn send(&mut self, req: Req, idempotent: bool) -> Result<Vec<Value>, YeeError> {
+        let mut retries = 0;
+        loop {
+            let result = match self.reconnect() {
+                Ok(()) => {
+                    let result = self.send_once(req.clone());
+                    if !idempotent {
+                        return result;
+                    }
+                    result
+                }
+                // nothing was sent, so any request can be retried
+                Err(e) => Err(e)
+            };
+            match result {
+                Err(YeeError::IoError { .. }) | Err(YeeError::Timeout { .. })
+                if retries < self.retry.max_retries() => {
+                    retries += 1;
+                    self.back_off(retries)?;
+                }
+                result => return result
+            }
+        }
+    }
+
+    /// Sends the request over the open connection, dropping the connection if it broke or the response timed out.
+    fn send_once(&mut self, req: Req) -> Result<Vec<Value>, YeeError> {
+        self.take_quota()?;
+        let timeout = self.response_timeout()?;
+        let result = match self.conn.as_mut() {
+            Some(conn) => conn.send(req).and_then(|p| p.receive(timeout)),
+            None => Err(io::Error::from(io::ErrorKind::NotConnected).into())
+        };
+        let result = match result {
+            Ok((seq, res)) => {
+                self.apply_notifications_before(seq);
+                return res.into_result();
+            }
+            Err(e) => {
+                self.apply_notifications();
+                Err(e)
+            }
+        };
+        // a late response may still arrive, or the connection may be half-open, so it isn't reused
+        self.conn = None;
+        result
+    }
+
+    /// The connection to the light, reconnecting first if the light closed it.
+    pub(crate) fn connection(&mut self) -> Result<&mut Connection, YeeError> {
+        let mut retries = 0;
+        loop {
+            match self.reconnect() {
+                Ok(()) => return Ok(self.conn.as_mut().unwrap()),
+                Err(e) if retries >= self.retry.max_retries() => return Err(e),
+                Err(_) => {
+                    retries += 1;
+                    self.back_off(retries)?;
+                }
+            }
+        }
+    }
+
+    /// Connects once, unless the connection is still open.
+    fn reconnect(&mut self) -> Result<(), YeeError> {
+        if self.conn.as_ref().is_some_and(Connection::is_closed) {
+            self.conn = None;
+        }
+        self.init()
+    }
+
+    /// Waits before the given retry, for no longer than the deadline leaves.
+    fn back_off(&self, retry: u32) -> Result<(), YeeError> {
+        let backoff = self.retry.backoff(retry);
+        match self.remaining() {
+            Some(remaining) if remaining == Duration::from_secs(0) =>
+                return Err(YeeError::Timeout { operation: "reconnect" }),
+            Some(remaining) => sleep(backoff.min(remaining)),
+            None => sleep(backoff)
+        }
+        Ok(())
+    }
+
+    /// The time left until the deadline, if there is one.
+    fn remaining(&self) -> Option<Duration> {
+        self.deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+
+    /// How long to wait for the next response, the read timeout cut short by the deadline if there is one.
+    fn response_timeout(&self) -> Result<Option<Duration>, YeeError> {
+        let remaining = match self.remaining() {
+            Some(remaining) => remaining,
+            None => return Ok(self.read_timeout)
+        };
+        if remaining == Duration::from_secs(0) {
+            return Err(YeeError::Timeout { operation: "receive a response" });
+        }
+        Ok(Some(self.read_timeout.map_or(remaining, |t| t.min(remaining))))
+    }
+
+    /// Applies the notifications the connection received so far to the cached fields.
+    fn apply_notifications(&mut self) {
+        let changes = match &self.conn {
+            Some(conn) => conn.changes.take_all(),
+            None => return
+        };
+        self.apply_all(changes.into_iter().map(|c| (c.property, c.value)));
+    }
+
+    /// Applies the notifications received before the line at `seq`, i.e. before the response to a request.
+    ///
+    /// Later ones are left for the next call or `poll_notifications`, so they are applied after the request's changes.
+    fn apply_notifications_before(&mut self, seq: u64) {
+        let changes = match &self.conn {
+            Some(conn) => conn.changes.take_before(seq),
+            None => return
+        };
+        self.apply_all(changes.into_iter().map(|c| (c.property, c.value)));
+    }
+
+    pub fn location(&self) -> &SocketAddr {
+        &self.location
+    }
+
+    /// The address of the local interface the light was found on, when the search was on chosen interfaces.
+    pub fn interface(&self) -> Option<Ipv4Addr> {
+        self.interface
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn model(&self) -> &str {
+        &self.model
+    }
+
+    pub fn fw_ver(&self) -> u8 {
+        self.fw_ver
+    }
+
+    pub fn support(&self) -> &HashSet<String> {
+        &self.support
+    }
+
+    pub fn power(&self) -> &PowerStatus {
+        &self.power
+    }
+
+    pub fn bright(&self) -> u8 {
+        self.bright
+    }
+
+    pub fn color_mode(&self) -> &ColorMode {
+        &self.color_mode
+    }
+
+    pub fn ct(&self) -> u16 {
+        self.ct
+    }
+
+    pub fn rgb(&self) -> &Rgb {
+        &self.rgb
+    }
+
+    pub fn hue(&self) -> u16 {
+        self.hue
+    }
+
+    pub fn sat(&self) -> u8 {
+        self.sat
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Only known for lights with a night light, once `refresh` has been called.
+    pub fn active_mode(&self) -> Option<ActiveMode> {
+        self.active_mode
+    }
+
+    /// The night light brightness, only known for lights with a night light once `refresh` has been called.
+    pub fn nl_br(&self) -> Option<u8> {
+        self.nl_br
+    }
+
+    /// The cached properties that may be out of date because they couldn't be re-read after an adjust.
+    ///
+    /// A property is no longer stale once it is read again, e.g. with `refresh`, or reported in a notification.
+    pub fn stale(&self) -> &HashSet<Property> {
+        &self.stale
+    }
+
+    pub fn connect_timeout(&self) -> Option<Duration> {
+        self.connect_timeout
+    }
+
+    pub fn read_timeout(&self) -> Option<Duration> {
+        self.read_timeout
+    }
+
+    pub fn write_timeout(&self) -> Option<Duration> {
+        self.write_timeout
+    }
+
+    pub fn retry_policy(&self) -> &RetryPolicy {
+        &self.retry
+    }
+
+    pub fn rate_limit(&self) -> Option<&RateLimit> {
+        self.limiter.as_ref().map(TokenBucket::limit)
+    }
+
+    /// How much of the command quota is in use, or `None` without a rate limit.
+    pub fn quota(&self) -> Option<QuotaUsage> {
+        self.limiter.as_ref().map(|l| QuotaUsage { used: l.used(), budget: l.limit().budget(), queued: self.queued.len() })
+    }
+}
+
+/// The properties read by `Light::connect`.
+const CONNECT_PROPS: &[Property] = &[Property::Power, Property::Bright, Property::ColorMode, Property::Ct,
+    Property::Rgb, Property::Hue, Property::Sat, Property::Name, Property::MusicOn, Property::ActiveMode,
+    Property::NlBr, Property::BgPower, Property::BgBright, Property::BgColorMode, Property::BgCt, Property::BgRgb,
+    Property::BgHue, Property::BgSat];
+
+/// The methods a light supports, going by the properties it reported.
+fn supported_methods(values: &HashMap<Property, PropValue>) -> HashSet<String> {
+    // every light has a brightness, power and name, which is empty until set
+    let mut methods = vec!["get_prop", "set_power", "toggle", "set_bright", "adjust_bright", "set_adjust",
+                           "start_cf", "stop_cf", "set_scene", "set_default", "set_name"];
+    let has = |prop| values.contains_key(&prop);
+    if has(Property::Ct) {
+        methods.extend_from_slice(&["set_ct_abx", "adjust_ct"]);
+    }
+    if has(Property::Rgb) {
+        methods.extend_from_slice(&["set_rgb", "set_hsv", "adjust_color"]);
+    }
+    if has(Property::MusicOn) {
+        methods.push("set_music");
+    }
+    if has(Property::BgPower) {
+        methods.extend_from_slice(&["bg_set_power", "bg_toggle", "bg_set_bright", "bg_adjust_bright", "bg_set_adjust",
+            "bg_start_cf", "bg_stop_cf", "bg_set_scene", "bg_set_default", "dev_toggle"]);
+        if has(Property::BgCt) {
+            methods.extend_from_slice(&["bg_set_ct_abx", "bg_adjust_ct"]);
+        }
+        if has(Property::BgRgb) {
+            methods.extend_from_slice(&["bg_set_rgb", "bg_set_hsv", "bg_adjust_color"]);
+        }
+    }
+    methods.into_iter().map(|m| m.to_string()).collect()
+}
+
+/// Sockets don't accept a zero timeout, `None` is used to wait forever instead.
+fn check_timeout(timeout: Option<Duration>) -> Result<Option<Duration>, YeeError> {
+    if timeout == Some(Duration::from_secs(0)) {
+        return Err(YeeError::InvalidValue { field_name: "timeout", value: "0s".to_string() });
+    }
+    Ok(timeout)
+}
+
+/// Fails unless the light answered a change with `["ok"]`.
+pub(crate) fn expect_ok(result: Vec<Value>) -> Result<(), YeeError> {
+    if result != [json!("ok")] {
+        return Err(YeeError::ChangeFailed { message: Value::from(result).to_string() });
+    }
+    Ok(())
+}
+
+/// Decodes the result of a `get_prop` request for the given properties, leaving out empty values.
+pub(crate) fn decode_props(props: &[Property], values: Vec<Value>) -> Result<HashMap<Property, PropValue>, YeeError> {
+    if values.len() != props.len() {
+        return Err(YeeError::ParseFieldFailed { field_name: "result", source: None });
+    }
+
+    let mut decoded = HashMap::new();
+    for (prop, value) in props.iter().zip(values) {
+        let raw = match value {
+            Value::String(s) => s,
+            other => other.to_string(),
+        };
+        if !raw.is_empty() {
+            decoded.insert(*prop, prop.decode(&raw)?);
+        }
+    }
+    Ok(decoded)
+}
+
+/// Decodes the result of a `cron_get` request.
+pub(crate) fn decode_sleep_timer(result: Vec<Value>) -> Result<Option<Duration>, YeeError> {
+    let minutes = match result.first() {
+        None => 0,
+        Some(job) => job.get("delay")
+            .and_then(|d| d.as_u64())
+            .ok_or(YeeError::FieldNotFound { field_name: "delay" })?
+    };
+    match minutes {
+        0 => Ok(None),
+        m => m.checked_mul(60)
+            .map(|secs| Some(Duration::from_secs(secs)))
+            .ok_or_else(|| YeeError::InvalidValue { field_name: "delay", value: m.to_string() })
+    }
+}
+
+/// The cached properties of the background light.
+pub(crate) const BG_PROPS: &[Property] = &[Property::BgPower, Property::BgBright, Property::BgColorMode,
+    Property::BgCt, Property::BgRgb, Property::BgHue, Property::BgSat];
+
+/// The cached properties that can change when adjusting the given property.
+pub(crate) fn adjusted_props(prop: AdjustProp) -> &'static [Property] {
+    match prop {
+        AdjustProp::Bright => &[Property::Bright],
+        AdjustProp::Ct => &[Property::Ct, Property::ColorMode],
+        AdjustProp::Color => &[Property::Rgb, Property::Hue, Property::Sat, Property::ColorMode],
+    }
+}
+
+impl Hash for Light {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write(self.id.as_bytes());
+    }
+}
+
+impl PartialEq for Light {
+    fn eq(&self, other: &Self) -> bool {
+        self.id.eq(&other.id)
+    }
+}
+
+impl Eq for Light {}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::collections::HashMap;
+    use std::net::{IpAddr, Ipv4Addr, SocketAddrV4, TcpListener, TcpStream};
+    use std::io::{BufRead, BufReader, Write};
+    use std::thread::{self, JoinHandle};
+
+    use super::*;
+
+    macro_rules! map {
+        ($($key:expr => $value: expr), *) => {{
+            let mut map = HashMap::new();
+            $(map.insert($key,$value);)*
+            map
+        }};
+    }
+
+    pub(crate) fn get_map() -> HashMap<&'static str, &'static str> {
+        let mut m: HashMap<&str, &str> =
+            map!(
+            "id" => "0x1234",
+            "model" => "floor",
+            "fw_ver" => "40", // can fail
+            "power" => "on", // can fail
+            "bright" => "34", // can fail
+            "color_mode" => "2", // can fail
+            "ct" => "0", // can fail
+            "rgb" => "657930", // 0A0A0A, can fail
+            "hue" => "314", // can fail
+            "sat" => "12", // can fail
+            "name" => "room_light",
+            "Location" => "yeelight://127.0.0.1:13454"
+            );
+        let support = "get_power set_power get_rgb set_rgb";
+        m.insert("support", support);
+        m
+    }
+
+    /// Starts a fake light that answers each request with the next reply, in order.
+    /// A reply is the JSON that follows the id, e.g. `"result":["ok"]`,
+    /// or whole lines where `{id}` is replaced with the request id.
+    /// The handle returns every request line that was received.
+    /// The connection is closed once every reply was sent.
+    pub(crate) fn fake_light(replies: Vec<&'static str>) -> anyhow::Result<(SocketAddrV4, JoinHandle<Vec<Value>>)> {
+        let listener = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))?;
+        let addr = match listener.local_addr()? {
+            SocketAddr::V4(v4) => v4,
+            _ => unreachable!()
+        };
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            serve(stream, replies)
+        });
+        Ok((addr, handle))
+    }
+
+    /// Like `fake_light`, but the first connection is closed after receiving a request without answering it.
+    pub(crate) fn dropping_light(replies: Vec<&'static str>) -> anyhow::Result<(SocketAddrV4, JoinHandle<Vec<Value>>)> {
+        let listener = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))?;
+        let addr = match listener.local_addr()? {
+            SocketAddr::V4(v4) => v4,
+            _ => unreachable!()
+        };
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut line = String::new();
+            BufReader::new(stream).read_line(&mut line).unwrap();
+            let (stream, _) = listener.accept().unwrap();
+            serve(stream, replies)
+        });
+        Ok((addr, handle))
+    }
+
+    fn serve(stream: TcpStream, replies: Vec<&'static str>) -> Vec<Value> {
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut writer = stream;
+        let mut received = Vec::new();
+        for reply in replies {
+            let mut line = String::new();
+            if reader.read_line(&mut line).unwrap() == 0 {
+                break;
+            }
+            let req: Value = serde_json::from_str(&line).unwrap();
+            if reply.contains("{id}") {
+                write!(writer, "{}\r\n", reply.replace("{id}", &req["id"].to_string())).unwrap();
+            } else {
+                write!(writer, "{{\"id\":{},{}}}\r\n", req["id"], reply).unwrap();
+            }
+            received.push(req);
+        }
+        received
+    }
+
+    /// Creates a light connected to a `fake_light` that supports the given methods.
+    pub(crate) fn connected_light(support: &'static str, replies: Vec<&'static str>)
+                                  -> anyhow::Result<(Light, JoinHandle<Vec<Value>>)> {
+        let (addr, handle) = fake_light(replies)?;
+        let light = light_at(SocketAddr::V4(addr), support)?;
+        Ok((light, handle))
+    }
+
+    /// Creates a light connected to the given address that supports the given methods.
+    pub(crate) fn light_at(addr: SocketAddr, support: &'static str) -> anyhow::Result<Light> {
+        let location = format!("yeelight://{}", addr);
+        let mut map: HashMap<&str, &str> = get_map();
+        map.insert("support", support);
+        map.insert("Location", &location);
+        let mut light = Light::from_fields(&map)?;
+        light.init()?;
+        Ok(light)
+    }
+
+    #[test]
+    fn send_correct_req() -> anyhow::Result<()> {
+        // given
+        let (mut light, handle) = connected_light("set_bright", vec![r#""result":["ok"]"#])?;
+
+        // when
+        light.set_bright(75, Transition::sudden())?;
+
+        // then
+        let received = handle.join().unwrap();
+        assert_eq!(received[0]["method"], "set_bright");
+        assert_eq!(received[0]["params"], json!([75, "sudden", 0]));
+        assert_eq!(light.bright(), 75);
+        Ok(())
+    }
+
+    #[test]
+    fn start_and_stop_flow() -> anyhow::Result<()> {
+        // given
+        let (mut light, handle) = connected_light(
+            "start_cf stop_cf", vec![r#""result":["ok"]"#, r#""result":["ok"]"#])?;
+        let flow = Flow::parse(6, crate::flow::FlowAction::Off, "1000,2,2700,100,500,1,255,10")?;
+
+        // when
+        light.start_cf(&flow)?;
+        light.stop_cf()?;
+
+        // then
+        let received = handle.join().unwrap();
+        assert_eq!(received[0]["method"], "start_cf");
+        assert_eq!(received[0]["params"], json!([6, 2, "1000,2,2700,100,500,1,255,10"]));
+        assert_eq!(received[1]["method"], "stop_cf");
+        Ok(())
+    }
+
+    #[test]
+    fn set_scene_updates_fields() -> anyhow::Result<()> {
+        // given
+        let (mut light, handle) = connected_light("set_scene", vec![r#""result":["ok"]"#])?;
+        light.power = PowerStatus::Off;
+
+        // when
+        light.set_scene(&Scene::Hsv { hue: 200, sat: 60, bright: 30 })?;
+
+        // then
+        let received = handle.join().unwrap();
+        assert_eq!(received[0]["params"], json!(["hsv", 200, 60, 30]));
+        assert_eq!(light.power(), &PowerStatus::On);
+        assert_eq!(light.color_mode(), &ColorMode::Hsv);
+        assert_eq!(light.hue(), 200);
+        assert_eq!(light.sat(), 60);
+        assert_eq!(light.bright(), 30);
+        Ok(())
+    }
+
+    #[test]
+    fn sleep_timer_round_trip() -> anyhow::Result<()> {
+        // given
+        let (mut light, handle) = connected_light(
+            "cron_add cron_get cron_del",
+            vec![r#""result":["ok"]"#,
+                 r#""result":[{"type":0,"delay":14,"mix":0}]"#,
+                 r#""result":["ok"]"#,
+                 r#""result":[]"#])?;
+
+        // when
+        light.set_sleep_timer(Duration::from_secs(15 * 60 - 20))?;
+        let remaining = light.sleep_timer()?;
+        light.cancel_sleep_timer()?;
+        let cancelled = light.sleep_timer()?;
+
+        // then
+        let received = handle.join().unwrap();
+        assert_eq!(received[0]["params"], json!([0, 15]));
+        assert_eq!(received[2]["params"], json!([0, 0]));
+        assert_eq!(remaining, Some(Duration::from_secs(14 * 60)));
+        assert_eq!(cancelled, None);
+        Ok(())
+    }
+
+    #[test]
+    fn sleep_timer_not_supported() -> anyhow::Result<()> {
+        // given
+        let (mut light, _handle) = connected_light("cron_get", vec![])?;
+
+        // when
+        let result = light.set_sleep_timer(Duration::from_secs(60));
+
+        // then
+        assert!(matches!(result, Err(YeeError::MethodNotSupported { method_name: "cron_add" })));
+        Ok(())
+    }
+
+    #[test]
+    fn sleep_timer_out_of_range() {
+        // when
+        let result = decode_sleep_timer(vec![json!({"type": 0, "delay": u64::MAX, "mix": 0})]);
+
+        // then
+        assert!(matches!(result, Err(YeeError::InvalidValue { field_name: "delay", .. })));
+    }
+
+    #[test]
+    fn adjust_resyncs_wrapped_value() -> anyhow::Result<()> {
+        // given
+        let (mut light, handle) = connected_light(
+            "set_adjust get_prop", vec![r#""result":["ok"]"#, r#""result":["1"]"#])?;
+        light.bright = 100;
+
+        // when
+        light.adjust(AdjustAction::Circle, AdjustProp::Bright)?;
+
+        // then
+        let received = handle.join().unwrap();
+        assert_eq!(received[0]["params"], json!(["circle", "bright"]));
+        assert_eq!(received[1]["params"], json!(["bright"]));
+        assert_eq!(light.bright(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn adjust_bright_without_get_prop() -> anyhow::Result<()> {
+        // given
+        let (mut light, handle) = connected_light("adjust_bright", vec![r#""result":["ok"]"#])?;
+
+        // when
+        light.adjust_bright(-20, Duration::from_millis(500))?;
+
+        // then
+        let received = handle.join().unwrap();
+        assert_eq!(received[0]["params"], json!([-20, 500]));
+        assert_eq!(received.len(), 1);
+        assert_eq!(light.stale(), &[Property::Bright].iter().copied().collect());
+        Ok(())
+    }
+
+    #[test]
+    fn adjust_succeeds_when_resync_fails() -> anyhow::Result<()> {
+        // given
+        let (mut light, handle) = connected_light(
+            "adjust_ct get_prop get_prop",
+            vec![r#""result":["ok"]"#, r#""error":{"code":-1,"message":"client quota exceeded"}"#,
+                 r#""result":["4000","2"]"#])?;
+
+        // when
+        let adjusted = light.adjust_ct(10, Duration::from_millis(500));
+        let stale = light.stale().clone();
+        let values = light.get_prop(&[Property::Ct, Property::ColorMode])?;
+        light.apply_all(values);
+
+        // then
+        assert!(adjusted.is_ok());
+        assert_eq!(stale, [Property::Ct, Property::ColorMode].iter().copied().collect());
+        assert!(light.stale().is_empty());
+        assert_eq!(handle.join().unwrap().len(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn adjust_leaves_quota_alone() -> anyhow::Result<()> {
+        // given
+        let (mut light, handle) = connected_light("adjust_bright get_prop", vec![r#""result":["ok"]"#])?;
+        light.set_rate_limit(Some(RateLimit::new(1, Duration::from_secs(60), LimitMode::Error)?));
+
+        // when
+        light.adjust_bright(10, Duration::from_millis(500))?;
+
+        // then
+        assert_eq!(handle.join().unwrap().len(), 1);
+        assert!(light.stale().contains(&Property::Bright));
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_adjust() -> anyhow::Result<()> {
+        // given
+        let (mut light, _handle) = connected_light("set_adjust adjust_ct", vec![])?;
+
+        // then
+        assert!(light.adjust(AdjustAction::Increase, AdjustProp::Color).is_err());
+        assert!(light.adjust_ct(101, Duration::from_millis(500)).is_err());
+        assert!(light.adjust_ct(10, Duration::from_millis(10)).is_err());
+        assert!(light.adjust_color(10, Duration::from_millis(500)).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn set_name_updates_field() -> anyhow::Result<()> {
+        // given
+        let (mut light, handle) = connected_light("set_name set_default", vec![r#""result":["ok"]"#, r#""result":["ok"]"#])?;
+
+        // when
+        light.set_name("bedroom light")?;
+        light.save_as_default()?;
+
+        // then
+        let received = handle.join().unwrap();
+        assert_eq!(received[0]["params"], json!(["bedroom light"]));
+        assert_eq!(received[1]["method"], "set_default");
+        assert_eq!(light.name(), "bedroom light");
+        Ok(())
+    }
+
+    #[test]
+    fn non_ascii_name_sent_as_base64() -> anyhow::Result<()> {
+        // given
+        let (mut light, handle) = connected_light("set_name", vec![r#""result":["ok"]"#])?;
+        let mut fields = get_map();
+        fields.insert("name", "c2Fsw7Nu");
+
+        // when
+        light.set_name("salón")?;
+        let discovered = Light::from_fields(&fields)?;
+
+        // then
+        assert_eq!(handle.join().unwrap()[0]["params"], json!(["c2Fsw7Nu"]));
+        assert_eq!(light.name(), "salón");
+        assert_eq!(discovered.name(), "salón");
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_name() -> anyhow::Result<()> {
+        // given
+        let (mut light, _handle) = connected_light("set_name", vec![])?;
+
+        // then
+        assert!(light.set_name("").is_err());
+        assert!(light.set_name("two\r\nlines").is_err());
+        assert!(light.set_name(&"a".repeat(65)).is_err());
+        // 50 bytes, but 68 once encoded
+        assert!(light.set_name(&"ó".repeat(25)).is_err());
+        assert!(light.save_as_default().is_err());
+        assert_eq!(light.name(), "room_light");
+        Ok(())
+    }
+
+    #[test]
+    fn switch_to_moonlight() -> anyhow::Result<()> {
+        // given
+        let (mut light, handle) = connected_light(
+            "set_power get_prop",
+            vec![r#""result":["on","100","2","4000","0","0","0","ceiling","0","30"]"#,
+                 r#""result":["ok"]"#,
+                 r#""result":["ok"]"#])?;
+        light.refresh()?;
+
+        // when
+        light.set_active_mode(ActiveMode::Moonlight, Transition::sudden())?;
+        let moonlight = light.active_mode();
+        light.set_power_with_mode(PowerStatus::On, Transition::sudden(), PowerMode::Color)?;
+
+        // then
+        let received = handle.join().unwrap();
+        assert_eq!(received[1]["params"], json!(["on", "sudden", 0, 5]));
+        assert_eq!(received[2]["params"], json!(["on", "sudden", 0, 2]));
+        assert_eq!(moonlight, Some(ActiveMode::Moonlight));
+        assert_eq!(light.active_mode(), Some(ActiveMode::Daylight));
+        assert_eq!(light.color_mode(), &ColorMode::Color);
+        assert_eq!(light.nl_br(), Some(30));
+        Ok(())
+    }
+
+    #[test]
+    fn device_error_returned() -> anyhow::Result<()> {
+        // given
+        let (mut light, _handle) = connected_light(
+            "set_bright", vec![r#""error":{"code":-5000,"message":"general error"}"#])?;
+
+        // when
+        let result = light.set_bright(12, Transition::sudden());
+
+        // then
+        assert!(matches!(result, Err(YeeError::Device { code: -5000, ref message }) if message == "general error"));
+        assert_eq!(light.bright(), 34);
+        Ok(())
+    }
+
+    #[test]
+    fn call_returns_result() -> anyhow::Result<()> {
+        // given
+        let (mut light, handle) = connected_light("", vec![r#""result":[{"type":0,"delay":5,"mix":0}]"#])?;
+
+        // when
+        let result = light.call("cron_get", vec![json!(0)])?;
+
+        // then
+        assert_eq!(result, vec![json!({"type": 0, "delay": 5, "mix": 0})]);
+        assert_eq!(handle.join().unwrap()[0]["method"], "cron_get");
+        Ok(())
+    }
+
+    #[test]
+    fn call_all_pipelines_requests() -> anyhow::Result<()> {
+        // given
+        let (mut light, handle) = connected_light(
+            "", vec![r#""result":["on"]"#, r#""error":{"code":-1,"message":"unsupported"}"#])?;
+
+        // when
+        let results = light.call_all(vec![
+            Req::new("get_prop".to_string(), vec![json!("power")]),
+            Req::new("unknown".to_string(), vec![]),
+        ]);
+
+        // then
+        assert_eq!(results[0].as_ref().ok(), Some(&vec![json!("on")]));
+        assert!(matches!(results[1], Err(YeeError::Device { code: -1, .. })));
+        let received = handle.join().unwrap();
+        assert_ne!(received[0]["id"], received[1]["id"]);
+        Ok(())
+    }
+
+    #[test]
+    fn skips_unrelated_lines() -> anyhow::Result<()> {
+        // given
+        let (mut light, _handle) = connected_light(
+            "set_bright",
+            vec![r#"{"method":"props","params":{"bright":"12"}}
+{"id":1{id},"error":{"code":-1,"message":"not this one"}}
+{"id":{id},"result":["ok"]}"#])?;
+
+        // when
+        let result = light.set_bright(12, Transition::sudden());
+
+        // then
+        assert!(result.is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn notified_while_idle() -> anyhow::Result<()> {
+        // given
+        let listener = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))?;
+        let addr = listener.local_addr()?;
+        let (subscribed, wait_for_subscriber) = mpsc::channel();
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            wait_for_subscriber.recv().unwrap();
+            stream.write_all(b"{\"method\":\"props\",\"params\":{\"power\":\"off\"}}\r\n").unwrap();
+            // keeps the connection open until the light is dropped
+            let mut line = String::new();
+            BufReader::new(stream).read_line(&mut line).unwrap();
+        });
+        let mut light = light_at(addr, "")?;
+        let changes = light.subscribe();
+
+        // when
+        subscribed.send(())?;
+        let change = changes.recv_timeout(Duration::from_secs(1))?;
+        let before_poll = *light.power();
+        light.poll_notifications(Duration::from_millis(100))?;
+
+        // then
+        assert_eq!(change, PropertyChange { property: Property::Power, value: PropValue::Power(PowerStatus::Off) });
+        assert_eq!(before_poll, PowerStatus::On);
+        assert_eq!(light.power(), &PowerStatus::Off);
+        drop(light);
+        handle.join().unwrap();
+        Ok(())
+    }
+
+    #[test]
+    fn notifications_update_fields() -> anyhow::Result<()> {
+        // given
+        let (mut light, _handle) = connected_light(
+            "set_bright",
+            vec![r#"{"method":"props","params":{"power":"off","bg_power":"on"}}
+{"id":{id},"result":["ok"]}
+{"method":"props","params":{"ct":4200,"color_mode":2}}"#,
+                 // keeps the connection open
+                 r#""result":["ok"]"#])?;
+        let changes = light.subscribe();
+
+        // when
+        light.set_bright(50, Transition::sudden())?;
+        let power = *light.power();
+        let ct = light.ct();
+        let count = light.poll_notifications(Duration::from_millis(500))?;
+
+        // then
+        assert_eq!(power, PowerStatus::Off);
+        assert_eq!(ct, 0);
+        assert_eq!(count, 2);
+        assert_eq!(light.ct(), 4200);
+        assert_eq!(light.bg.power, PowerStatus::On);
+        let received: Vec<PropertyChange> = changes.try_iter().collect();
+        assert_eq!(received.len(), 4);
+        assert_eq!(received[0], PropertyChange { property: Property::BgPower, value: PropValue::Power(PowerStatus::On) });
+        Ok(())
+    }
+
+    #[test]
+    fn poll_without_notifications() -> anyhow::Result<()> {
+        // given
+        let (mut light, _handle) = connected_light("", vec![r#""result":["ok"]"#])?;
+
+        // when
+        let start = Instant::now();
+        let count = light.poll_notifications(Duration::from_millis(100))?;
+
+        // then
+        assert_eq!(count, 0);
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        Ok(())
+    }
+
+    /// Creates a light connected to a fake light that never responds, on any number of connections.
+    fn silent_light(support: &'static str) -> anyhow::Result<Light> {
+        let listener = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))?;
+        let addr = listener.local_addr()?;
+        thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                // until the light disconnects
+                thread::spawn(move || std::io::copy(&mut stream, &mut std::io::sink()));
+            }
+        });
+        light_at(addr, support)
+    }
+
+    #[test]
+    fn read_timeout_expires() -> anyhow::Result<()> {
+        // given
+        let mut light = silent_light("set_bright")?;
+        light.set_read_timeout(Some(Duration::from_millis(50)))?;
+
+        // when
+        let start = Instant::now();
+        let result = light.set_bright(12, Transition::sudden());
+
+        // then
+        assert!(matches!(result, Err(YeeError::Timeout { .. })));
+        assert!(start.elapsed() < DEFAULT_TIMEOUT);
+        assert_eq!(light.bright(), 34);
+        Ok(())
+    }
+
+    #[test]
+    fn deadline_cuts_read_timeout_short() -> anyhow::Result<()> {
+        // given
+        let mut light = silent_light("set_bright set_power")?;
+
+        // when
+        let start = Instant::now();
+        let result = light.with_deadline(Instant::now() + Duration::from_millis(100), |l| {
+            l.set_power(PowerStatus::Off, Transition::sudden())
+        });
+        let expired = light.with_deadline(Instant::now(), |l| l.set_bright(12, Transition::sudden()));
+
+        // then
+        assert!(matches!(result, Err(YeeError::Timeout { .. })));
+        assert!(matches!(expired, Err(YeeError::Timeout { .. })));
+        assert!(start.elapsed() < DEFAULT_TIMEOUT);
+        assert_eq!(light.read_timeout(), Some(DEFAULT_TIMEOUT));
+        assert!(light.set_write_timeout(Some(Duration::from_secs(0))).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn timeout_drops_connection() -> anyhow::Result<()> {
+        // given
+        let mut light = silent_light("toggle")?;
+        light.set_read_timeout(Some(Duration::from_millis(50)))?;
+
+        // when
+        let result = light.toggle();
+
+        // then
+        assert!(matches!(result, Err(YeeError::Timeout { .. })));
+        assert!(light.conn.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn deadline_caps_reconnecting() -> anyhow::Result<()> {
+        // given
+        let closed = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))?;
+        let addr = closed.local_addr()?;
+        let mut light = light_at(addr, "set_bright")?;
+        drop(closed);
+        light.conn = None;
+        light.set_retry_policy(RetryPolicy::new(5, Duration::from_secs(1), Duration::from_secs(1)));
+
+        // when
+        let start = Instant::now();
+        let result = light.with_deadline(Instant::now() + Duration::from_millis(100), |l| {
+            l.set_bright(12, Transition::sudden())
+        });
+
+        // then
+        assert!(matches!(result, Err(YeeError::Timeout { .. })));
+        assert!(start.elapsed() < Duration::from_millis(500));
+        Ok(())
+    }
+
+    #[test]
+    fn retries_absolute_setter_on_new_connection() -> anyhow::Result<()> {
+        // given
+        let (addr, handle) = dropping_light(vec![r#""result":["ok"]"#])?;
+        let mut light = light_at(SocketAddr::V4(addr), "set_bright")?;
+        light.set_retry_policy(RetryPolicy::new(2, Duration::from_millis(10), Duration::from_millis(10)));
+
+        // when
+        light.set_bright(60, Transition::sudden())?;
+
+        // then
+        let received = handle.join().unwrap();
+        assert_eq!(received[0]["method"], "set_bright");
+        assert_eq!(light.bright(), 60);
+        Ok(())
+    }
+
+    #[test]
+    fn retries_absolute_setter_after_timeout() -> anyhow::Result<()> {
+        // given
+        let listener = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))?;
+        let addr = listener.local_addr()?;
+        let handle = thread::spawn(move || {
+            // the first connection never answers
+            let (_silent, _) = listener.accept().unwrap();
+            let (stream, _) = listener.accept().unwrap();
+            serve(stream, vec![r#""result":["ok"]"#])
+        });
+        let mut light = light_at(addr, "set_bright")?;
+        light.set_read_timeout(Some(Duration::from_millis(50)))?;
+        light.set_retry_policy(RetryPolicy::new(2, Duration::from_millis(10), Duration::from_millis(10)));
+
+        // when
+        light.set_bright(60, Transition::sudden())?;
+
+        // then
+        assert_eq!(handle.join().unwrap()[0]["method"], "set_bright");
+        assert_eq!(light.bright(), 60);
+        Ok(())
+    }
+
+    #[test]
+    fn retries_share_one_budget() -> anyhow::Result<()> {
+        // given
+        let listener = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))?;
+        let addr = listener.local_addr()?;
+        let mut light = light_at(addr, "set_bright")?;
+        light.conn = None;
+        light.set_retry_policy(RetryPolicy::new(2, Duration::from_millis(10), Duration::from_millis(10)));
+        // closes every connection as soon as it is made
+        listener.set_nonblocking(true)?;
+        let handle = thread::spawn(move || {
+            let mut accepted = 0;
+            let end = Instant::now() + Duration::from_millis(500);
+            while Instant::now() < end {
+                if listener.accept().is_ok() {
+                    accepted += 1;
+                }
+                thread::sleep(Duration::from_millis(1));
+            }
+            accepted
+        });
+
+        // when
+        let result = light.set_bright(60, Transition::sudden());
+
+        // then
+        assert!(matches!(result, Err(YeeError::IoError { .. })));
+        // the connection made by light_at, then the first try and one per retry
+        assert_eq!(handle.join().unwrap(), 4);
+        Ok(())
+    }
+
+    #[test]
+    fn toggle_not_retried_but_reconnects() -> anyhow::Result<()> {
+        // given
+        let (addr, handle) = dropping_light(vec![r#""result":["ok"]"#])?;
+        let mut light = light_at(SocketAddr::V4(addr), "toggle set_bright")?;
+        light.set_retry_policy(RetryPolicy::new(2, Duration::from_millis(10), Duration::from_millis(10)));
+
+        // when
+        let toggled = light.toggle();
+        light.set_bright(60, Transition::sudden())?;
+
+        // then
+        assert!(matches!(toggled, Err(YeeError::IoError { .. })));
+        assert_eq!(light.power(), &PowerStatus::On);
+        let received = handle.join().unwrap();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0]["method"], "set_bright");
+        Ok(())
+    }
+
+    #[test]
+    fn rate_limit_errors_when_used_up() -> anyhow::Result<()> {
+        // given
+        let (mut light, _handle) = connected_light(
+            "set_bright", vec![r#""result":["ok"]"#, r#""result":["ok"]"#])?;
+        light.set_rate_limit(Some(RateLimit::new(1, Duration::from_secs(60), LimitMode::Error)?));
+
+        // when
+        light.set_bright(10, Transition::sudden())?;
+        let limited = light.set_bright(20, Transition::sudden());
+
+        // then
+        assert!(matches!(limited, Err(YeeError::RateLimited { .. })));
+        assert_eq!(light.bright(), 10);
+        assert_eq!(light.quota(), Some(QuotaUsage { used: 1, budget: 1, queued: 0 }));
+        Ok(())
+    }
+
+    #[test]
+    fn rate_limit_coalesces_setters() -> anyhow::Result<()> {
+        // given
+        let (mut light, handle) = connected_light(
+            "set_bright", vec![r#""result":["ok"]"#, r#""result":["ok"]"#])?;
+        light.set_rate_limit(Some(RateLimit::new(1, Duration::from_millis(200), LimitMode::Coalesce)?));
+
+        // when
+        light.set_bright(10, Transition::sudden())?;
+        light.set_bright(20, Transition::sudden())?;
+        light.set_bright(30, Transition::sudden())?;
+        let quota = light.quota();
+        let bright = light.bright();
+        light.flush()?;
+
+        // then
+        assert_eq!(quota.map(|q| q.queued), Some(1));
+        assert_eq!(bright, 10);
+        assert_eq!(light.bright(), 30);
+        let received = handle.join().unwrap();
+        assert_eq!(received.len(), 2);
+        assert_eq!(received[1]["params"], json!([30, "sudden", 0]));
+        Ok(())
+    }
+
+    #[test]
+    fn drop_discards_queued_commands() -> anyhow::Result<()> {
+        // given
+        let (mut light, handle) = connected_light(
+            "set_bright", vec![r#""result":["ok"]"#, r#""result":["ok"]"#])?;
+        light.set_rate_limit(Some(RateLimit::new(1, Duration::from_secs(60), LimitMode::Coalesce)?));
+        light.set_bright(10, Transition::sudden())?;
+        light.set_bright(20, Transition::sudden())?;
+
+        // when
+        let start = Instant::now();
+        drop(light);
+
+        // then
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(handle.join().unwrap().len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn read_sends_queued_commands_first() -> anyhow::Result<()> {
+        // given
+        let (mut light, handle) = connected_light(
+            "set_bright get_prop", vec![r#""result":["ok"]"#, r#""result":["ok"]"#, r#""result":["20"]"#])?;
+        light.set_rate_limit(Some(RateLimit::new(1, Duration::from_millis(100), LimitMode::Coalesce)?));
+        light.set_bright(10, Transition::sudden())?;
+        light.set_bright(20, Transition::sudden())?;
+
+        // when
+        let values = light.get_prop(&[Property::Bright])?;
+
+        // then
+        assert_eq!(values.get(&Property::Bright), Some(&PropValue::Bright(20)));
+        assert_eq!(light.quota().map(|q| q.queued), Some(0));
+        let received = handle.join().unwrap();
+        assert_eq!(received[1]["params"], json!([20, "sudden", 0]));
+        assert_eq!(received[2]["method"], "get_prop");
+        Ok(())
+    }
+
+    #[test]
+    fn no_rate_limit_by_default() -> anyhow::Result<()> {
+        // given
+        let (light, _handle) = connected_light("set_bright", vec![])?;
+
+        // then
+        assert!(light.rate_limit().is_none());
+        assert!(light.quota().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn queued_failure_kept_for_its_command() -> anyhow::Result<()> {
+        // given
+        let (mut light, handle) = connected_light(
+            "set_bright set_power",
+            vec![r#""result":["ok"]"#, r#""error":{"code":-1,"message":"general error"}"#, r#""result":["ok"]"#])?;
+        light.set_rate_limit(Some(RateLimit::new(1, Duration::from_millis(100), LimitMode::Coalesce)?));
+        light.set_bright(10, Transition::sudden())?;
+        light.set_bright(20, Transition::sudden())?;
+        thread::sleep(Duration::from_millis(150));
+
+        // when
+        let powered = light.set_power(PowerStatus::Off, Transition::sudden());
+        let failures = light.take_queue_failures();
+        light.flush()?;
+
+        // then
+        assert!(powered.is_ok());
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].method, "set_bright");
+        assert!(matches!(failures[0].error, YeeError::Device { code: -1, .. }));
+        assert_eq!(light.bright(), 10);
+        assert_eq!(light.power(), &PowerStatus::Off);
+        assert_eq!(handle.join().unwrap()[2]["method"], "set_power");
+        Ok(())
+    }
+
+    #[test]
+    fn queued_command_keeps_its_deadline() -> anyhow::Result<()> {
+        // given
+        let (mut light, handle) = connected_light(
+            "set_bright set_power", vec![r#""result":["ok"]"#, r#""result":["ok"]"#])?;
+        light.set_rate_limit(Some(RateLimit::new(1, Duration::from_millis(100), LimitMode::Coalesce)?));
+        light.set_power(PowerStatus::On, Transition::sudden())?;
+        light.with_deadline(Instant::now() + Duration::from_millis(50), |l| l.set_bright(20, Transition::sudden()))?;
+
+        // when
+        let flushed = light.flush();
+
+        // then
+        assert!(matches!(flushed, Err(YeeError::Timeout { .. })));
+        assert_eq!(light.bright(), 34);
+        drop(light);
+        assert_eq!(handle.join().unwrap().len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn connect_reads_discovery_response() -> anyhow::Result<()> {
+        // given
+        let (addr, handle) = fake_light(vec![r#""result":["on","60","2","3000","","","","desk","",""]"#])?;
+        let responder = std::net::UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))?;
+        let probe = responder.local_addr()?;
+        let response = crate::tests::search_response(SocketAddr::V4(addr));
+        let responder = thread::spawn(move || -> std::io::Result<String> {
+            let mut buf = [0; 512];
+            let (size, sender) = responder.recv_from(&mut buf)?;
+            responder.send_to(response.as_bytes(), sender)?;
+            Ok(String::from_utf8_lossy(&buf[..size]).to_string())
+        });
+
+        // when
+        let light = Light::connect_probing(SocketAddr::V4(addr), probe)?;
+
+        // then
+        assert!(responder.join().unwrap()?.starts_with("M-SEARCH"));
+        assert_eq!(handle.join().unwrap()[0]["method"], "get_prop");
+        assert_eq!(light.id(), "0x12345abcde");
+        assert_eq!(light.model(), "mono");
+        assert_eq!(light.fw_ver(), 20);
+        assert_eq!(light.support(), &["get_prop", "set_power"].iter().map(|m| m.to_string()).collect());
+        assert_eq!(light.bright(), 60);
+        assert_eq!(light.name(), "desk");
+        assert_eq!(light.location(), &SocketAddr::V4(addr));
+        Ok(())
+    }
+
+    #[test]
+    fn connect_reads_state_and_support() -> anyhow::Result<()> {
+        // given
+        let (addr, handle) = fake_light(vec![
+            r#""result":["on","70","2","3500","","","","","0","","","on","20","1","","255","",""]"#,
+            r#""error":{"code":-1,"message":"method not supported"}"#])?;
+
+        // when
+        let light = Light::connect(SocketAddr::V4(addr))?;
+
+        // then
+        let received = handle.join().unwrap();
+        assert_eq!(received[0]["method"], "get_prop");
+        assert_eq!(received[1]["method"], "cron_get");
+        assert_eq!(light.id(), addr.to_string());
+        assert_eq!(light.power(), &PowerStatus::On);
+        assert_eq!(light.bright(), 70);
+        assert_eq!(light.ct(), 3500);
+        assert_eq!(light.bg.rgb, Rgb::new(0, 0, 255));
+        assert!(light.support().contains("set_ct_abx"));
+        assert!(light.support().contains("set_music"));
+        assert!(light.support().contains("bg_set_rgb"));
+        assert!(!light.support().contains("set_rgb"));
+        assert!(!light.support().contains("bg_set_ct_abx"));
+        assert!(!light.support().contains("cron_get"));
+        Ok(())
+    }
+
+    #[test]
+    fn get_prop_decodes_values() -> anyhow::Result<()> {
+        // given
+        let (mut light, handle) = connected_light(
+            "get_prop", vec![r#""result":["off","","1","5"]"#])?;
+
+        // when
+        let values = light.get_prop(&[Property::Power, Property::NlBr, Property::MusicOn, Property::DelayOff])?;
+
+        // then
+        let received = handle.join().unwrap();
+        assert_eq!(received[0]["params"], json!(["power", "nl_br", "music_on", "delayoff"]));
+        assert_eq!(values.len(), 3);
+        assert_eq!(values[&Property::Power], PropValue::Power(PowerStatus::Off));
+        assert_eq!(values[&Property::MusicOn], PropValue::Flag(true));
+        assert_eq!(values[&Property::DelayOff], PropValue::Minutes(Duration::from_secs(300)));
+        Ok(())
+    }
+
+    #[test]
+    fn get_prop_not_supported() -> anyhow::Result<()> {
+        // given
+        let (mut light, _handle) = connected_light("set_power", vec![])?;
+
+        // when
+        let result = light.get_prop(&[Property::Power]);
+
+        // then
+        assert!(matches!(result, Err(YeeError::MethodNotSupported { method_name: "get_prop" })));
+        Ok(())
+    }
+
+    #[test]
+    fn refresh_updates_fields() -> anyhow::Result<()> {
+        // given
+        let (mut light, _handle) = connected_light(
+            "get_prop",
+            vec![r#""result":["off","80","1","4000","16711680","120","55","desk","",""]"#])?;
+
+        // when
+        light.refresh()?;
+
+        // then
+        assert_eq!(light.power(), &PowerStatus::Off);
+        assert_eq!(light.bright(), 80);
+        assert_eq!(light.color_mode(), &ColorMode::Color);
+        assert_eq!(light.ct(), 4000);
+        assert_eq!(light.rgb(), &Rgb::new(255, 0, 0));
+        assert_eq!(light.hue(), 120);
+        assert_eq!(light.sat(), 55);
+        assert_eq!(light.name(), "desk");
+        assert_eq!(light.active_mode(), None);
+        assert_eq!(light.nl_br(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn get_correct_location() -> anyhow::Result<()> {
+        // given
+        let map = get_map();
+        let expected_addr = SocketAddr::new(IpAddr::from(Ipv4Addr::LOCALHOST), 13454);
+
+        // when
+        let light = Light::from_fields(&map)?;
+
+        // then
+        assert_eq!(*light.location(), expected_addr);
+        Ok(())
+    }
+
+    #[test]
+    fn ipv6_location() -> anyhow::Result<()> {
+        // given
+        let listener = TcpListener::bind("[::1]:0")?;
+        let location = format!("yeelight://{}", listener.local_addr()?);
+        let mut map = get_map();
+        map.insert("Location", &location);
+
+        // when
+        let mut light = Light::from_fields(&map)?;
+        light.init()?;
+
+        // then
+        assert_eq!(light.location(), &listener.local_addr()?);
+        assert!(light.location().is_ipv6());
+        assert!(light.conn.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn unsupported_location() {
+        // given
+        let mut map = get_map();
+        // named scopes can't be parsed
+        map.insert("Location", "yeelight://[fe80::1%eth0]:55443");
+
+        // when
+        let result = Light::from_fields(&map);
+
+        // then
+        assert!(matches!(result, Err(YeeError::ParseFieldFailed { field_name: "Location", .. })));
     }
 
     macro_rules! generate_getter_tests {
@@ -471,8 +2456,7 @@ mod tests {
         light.init()?;
 
         // then
-        assert!(light.read.is_some());
-        assert!(light.write.is_some());
+        assert!(light.conn.is_some());
         Ok(())
     }
 }
@@ -0,0 +1,158 @@
+use std::time::{Duration, Instant};
+
+use crate::err::YeeError;
+
+/// What a `Light` does with a command once its quota is used up.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum LimitMode {
+    /// Waits until the quota allows another command.
+    Block,
+    /// Fails with `YeeError::RateLimited`.
+    Error,
+    /// Queues absolute setters, only keeping the latest command for each method,
+    /// e.g. only the last of several `set_bright` is sent.
+    /// Queued commands are sent before the next command or with `Light::flush`,
+    /// each within the deadline it was queued under, and discarded if the light is dropped first.
+    /// Other commands wait like `Block`.
+    Coalesce,
+}
+
+/// A client-side limit on the commands sent to a light.
+///
+/// Lights accept about 60 commands per minute and start refusing connections after that.
+/// Up to `budget` commands can be sent at once, after which one is allowed every `period / budget`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct RateLimit {
+    budget: u32,
+    period: Duration,
+    mode: LimitMode,
+}
+
+impl RateLimit {
+    pub fn new(budget: u32, period: Duration, mode: LimitMode) -> Result<RateLimit, YeeError> {
+        if budget == 0 {
+            return Err(YeeError::InvalidValue { field_name: "budget", value: budget.to_string() });
+        } else if period == Duration::from_secs(0) {
+            return Err(YeeError::InvalidValue { field_name: "period", value: format!("{:?}", period) });
+        }
+        Ok(RateLimit { budget, period, mode })
+    }
+
+    pub fn budget(&self) -> u32 {
+        self.budget
+    }
+
+    pub fn period(&self) -> Duration {
+        self.period
+    }
+
+    pub fn mode(&self) -> LimitMode {
+        self.mode
+    }
+}
+
+impl Default for RateLimit {
+    /// The device quota of 60 commands per minute, blocking once it is used up.
+    fn default() -> Self {
+        RateLimit { budget: 60, period: Duration::from_secs(60), mode: LimitMode::Block }
+    }
+}
+
+/// How much of the command quota of a light is in use, from `Light::quota`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct QuotaUsage {
+    /// commands that count against the budget right now
+    pub used: u32,
+    pub budget: u32,
+    /// commands waiting to be sent with `LimitMode::Coalesce`
+    pub queued: usize,
+}
+
+/// A command queued by `LimitMode::Coalesce` that failed once a later call sent it, from `Light::take_queue_failures`.
+#[derive(Debug)]
+pub struct QueuedFailure {
+    pub method: String,
+    pub error: YeeError,
+}
+
+/// A token bucket holding up to `budget` commands, refilled over `period`.
+#[derive(Debug)]
+pub(crate) struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub(crate) fn new(limit: RateLimit) -> TokenBucket {
+        TokenBucket { limit, tokens: f64::from(limit.budget), updated: Instant::now() }
+    }
+
+    pub(crate) fn limit(&self) -> &RateLimit {
+        &self.limit
+    }
+
+    fn available(&self, now: Instant) -> f64 {
+        let refilled = now.saturating_duration_since(self.updated).as_secs_f64()
+            * f64::from(self.limit.budget) / self.limit.period.as_secs_f64();
+        (self.tokens + refilled).min(f64::from(self.limit.budget))
+    }
+
+    pub(crate) fn has_token(&self) -> bool {
+        self.available(Instant::now()) >= 1.0
+    }
+
+    /// Takes a token, or returns how long until the next one is available.
+    pub(crate) fn try_take(&mut self) -> Result<(), Duration> {
+        let now = Instant::now();
+        self.tokens = self.available(now);
+        self.updated = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            let missing = 1.0 - self.tokens;
+            Err(self.limit.period.mul_f64(missing / f64::from(self.limit.budget)))
+        }
+    }
+
+    pub(crate) fn used(&self) -> u32 {
+        self.limit.budget - self.available(Instant::now()).floor() as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread::sleep;
+
+    use super::*;
+
+    #[test]
+    fn empties_and_refills() -> anyhow::Result<()> {
+        // given
+        let mut bucket = TokenBucket::new(RateLimit::new(2, Duration::from_millis(100), LimitMode::Error)?);
+
+        // when
+        let first = bucket.try_take();
+        let second = bucket.try_take();
+        let third = bucket.try_take();
+        let used = bucket.used();
+        sleep(Duration::from_millis(60));
+
+        // then
+        assert_eq!(first, Ok(()));
+        assert_eq!(second, Ok(()));
+        assert!(matches!(third, Err(wait) if wait <= Duration::from_millis(50)));
+        assert_eq!(used, 2);
+        assert!(bucket.has_token());
+        assert_eq!(bucket.used(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_limit() {
+        // then
+        assert!(RateLimit::new(0, Duration::from_secs(1), LimitMode::Block).is_err());
+        assert!(RateLimit::new(1, Duration::from_secs(0), LimitMode::Block).is_err());
+    }
+}
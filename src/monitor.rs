@@ -0,0 +1,266 @@
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::net::{Ipv4Addr, SocketAddrV4, UdpSocket};
+use std::time::{Duration, Instant};
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::{MULTICAST_ADDR, MULTICAST_PORT};
+use crate::err::YeeError;
+use crate::light::Light;
+
+// used when an advertisement has no Cache-Control header
+const DEFAULT_MAX_AGE: Duration = Duration::from_secs(3600);
+
+// the headers that make an advertisement an update, the others change with every one
+const TRACKED_HEADERS: &[&str] = &["Location", "model", "fw_ver", "support", "power", "bright", "color_mode",
+    "ct", "rgb", "hue", "sat", "name"];
+
+/// A change in the lights present on the network, from `YeeMonitor::poll`.
+#[derive(Debug)]
+pub enum PresenceEvent {
+    /// A light advertised itself for the first time, or again after it expired.
+    Appeared(Light),
+    /// A known light advertised a new address or state.
+    Updated(Light),
+    /// A light said goodbye, or didn't advertise itself again within its max-age.
+    Expired { id: String },
+}
+
+#[derive(Debug)]
+struct Device {
+    headers: HashMap<String, String>,
+    expires: Instant,
+}
+
+/// Listens for the `NOTIFY` advertisements lights multicast on their own, keeping track of each light by id.
+///
+/// Unlike `YeeClient`, nothing is sent, and the lights in the events are not connected yet.
+#[derive(Debug)]
+pub struct YeeMonitor {
+    socket: UdpSocket,
+    devices: HashMap<String, Device>,
+}
+
+impl YeeMonitor {
+    pub fn new() -> Result<YeeMonitor, YeeError> {
+        Self::with_addr(SocketAddrV4::new(MULTICAST_ADDR, MULTICAST_PORT))
+    }
+
+    /// Listens on the port of `multicast_addr`, where the advertisements are sent.
+    ///
+    /// The port is shared, so a monitor can run alongside a `YeeClient` or other monitors.
+    pub fn with_addr(multicast_addr: SocketAddrV4) -> Result<YeeMonitor, YeeError> {
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_reuse_address(true)?;
+        #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+        socket.set_reuse_port(true)?;
+        socket.bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, multicast_addr.port()).into())?;
+        socket.join_multicast_v4(multicast_addr.ip(), &Ipv4Addr::UNSPECIFIED)?;
+
+        Ok(YeeMonitor { socket: socket.into(), devices: HashMap::new() })
+    }
+
+    /// Listens for `timeout`, returning the events in the order they happened.
+    pub fn poll(&mut self, timeout: Duration) -> Result<Vec<PresenceEvent>, YeeError> {
+        let start = Instant::now();
+        let mut events = Vec::new();
+        let mut buf = [0u8; 1024];
+        while let Some(remaining) = timeout.checked_sub(start.elapsed()).filter(|r| *r > Duration::from_secs(0)) {
+            self.socket.set_read_timeout(Some(remaining))?;
+            match self.socket.recv_from(&mut buf) {
+                Ok((size, _)) => {
+                    if let Some(notify) = parse_notify(&buf[..size]) {
+                        self.expire(&mut events);
+                        self.update(notify, &mut events);
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => break,
+                Err(e) => return Err(e.into())
+            }
+        }
+        self.expire(&mut events);
+        Ok(events)
+    }
+
+    /// The ids of the lights currently present.
+    pub fn ids(&self) -> impl Iterator<Item=&str> {
+        self.devices.keys().map(|id| id.as_str())
+    }
+
+    fn update(&mut self, notify: Notify, events: &mut Vec<PresenceEvent>) {
+        let id = match notify.headers.get("id") {
+            Some(id) => id.clone(),
+            None => return
+        };
+        if !notify.alive {
+            if self.devices.remove(&id).is_some() {
+                events.push(PresenceEvent::Expired { id });
+            }
+            return;
+        }
+        // advertisements that aren't a valid light are ignored
+        let light = match Light::from_fields(&notify.headers.iter().map(|(k, v)| (k.as_str(), v)).collect()) {
+            Ok(light) => light,
+            Err(_) => return
+        };
+        let expires = Instant::now() + notify.max_age;
+        let previous = self.devices.insert(id, Device { headers: notify.headers, expires });
+        match previous {
+            None => events.push(PresenceEvent::Appeared(light)),
+            Some(previous) => {
+                let current = &self.devices[light.id()].headers;
+                if TRACKED_HEADERS.iter().any(|h| previous.headers.get(*h) != current.get(*h)) {
+                    events.push(PresenceEvent::Updated(light));
+                }
+            }
+        }
+    }
+
+    fn expire(&mut self, events: &mut Vec<PresenceEvent>) {
+        let now = Instant::now();
+        let mut expired: Vec<String> = self.devices.iter()
+            .filter(|(_, device)| device.expires <= now)
+            .map(|(id, _)| id.clone())
+            .collect();
+        expired.sort();
+        for id in expired {
+            self.devices.remove(&id);
+            events.push(PresenceEvent::Expired { id });
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Notify {
+    headers: HashMap<String, String>,
+    max_age: Duration,
+    // false for ssdp:byebye
+    alive: bool,
+}
+
+/// Parses a `NOTIFY` advertisement, or returns `None` for anything else, e.g. another client's `M-SEARCH`.
+fn parse_notify(buf: &[u8]) -> Option<Notify> {
+    let mut headers = [httparse::EMPTY_HEADER; 24];
+    let mut req = httparse::Request::new(&mut headers);
+    req.parse(buf).ok()?;
+    if req.method != Some("NOTIFY") {
+        return None;
+    }
+    let headers: HashMap<String, String> = req.headers.iter()
+        .map(|h| (h.name.to_string(), String::from_utf8_lossy(h.value).into_owned()))
+        .collect();
+    let header = |name: &str| headers.iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.as_str());
+
+    let max_age = header("Cache-Control")
+        .and_then(|v| v.trim().strip_prefix("max-age="))
+        .and_then(|v| v.trim().parse().ok())
+        .map_or(DEFAULT_MAX_AGE, Duration::from_secs);
+    let alive = header("NTS") != Some("ssdp:byebye");
+    Some(Notify { headers, max_age, alive })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn notify(max_age: u64, bright: u8) -> String {
+        format!("NOTIFY * HTTP/1.1\r
+Host: 239.255.255.250:1982\r
+Cache-Control: max-age={}\r
+Location: yeelight://127.0.0.1:55443\r
+NTS: ssdp:alive\r
+Server: POSIX, UPnP/1.0 YGLC/1\r
+id: 0x000000000015243f\r
+model: color\r
+fw_ver: 18\r
+support: get_prop set_default set_power toggle set_bright\r
+power: on\r
+bright: {}\r
+color_mode: 2\r
+ct: 4000\r
+rgb: 16711680\r
+hue: 100\r
+sat: 35\r
+name: my_bulb\r
+\r
+", max_age, bright)
+    }
+
+    fn local_monitor() -> anyhow::Result<(YeeMonitor, UdpSocket)> {
+        let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))?;
+        let sender = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))?;
+        sender.connect(socket.local_addr()?)?;
+        Ok((YeeMonitor { socket, devices: HashMap::new() }, sender))
+    }
+
+    #[test]
+    fn monitors_share_port() -> anyhow::Result<()> {
+        // given
+        let multicast_addr = SocketAddrV4::new(MULTICAST_ADDR, 21982);
+
+        // when
+        let first = YeeMonitor::with_addr(multicast_addr)?;
+        let second = YeeMonitor::with_addr(multicast_addr)?;
+
+        // then
+        assert_eq!(first.socket.local_addr()?.port(), 21982);
+        assert_eq!(second.socket.local_addr()?.port(), 21982);
+        Ok(())
+    }
+
+    #[test]
+    fn appear_and_update() -> anyhow::Result<()> {
+        // given
+        let (mut monitor, sender) = local_monitor()?;
+        sender.send(notify(3600, 50).as_bytes())?;
+        sender.send(b"M-SEARCH * HTTP/1.1\r\nHOST: 239.255.255.250:1982\r\n\r\n")?;
+        sender.send(notify(3600, 50).as_bytes())?;
+        sender.send(notify(3600, 80).as_bytes())?;
+
+        // when
+        let events = monitor.poll(Duration::from_millis(200))?;
+
+        // then
+        assert_eq!(events.len(), 2);
+        assert!(matches!(&events[0], PresenceEvent::Appeared(light) if light.bright() == 50));
+        assert!(matches!(&events[1], PresenceEvent::Updated(light) if light.bright() == 80));
+        assert_eq!(monitor.ids().collect::<Vec<_>>(), vec!["0x000000000015243f"]);
+        Ok(())
+    }
+
+    #[test]
+    fn expire_after_max_age() -> anyhow::Result<()> {
+        // given
+        let (mut monitor, sender) = local_monitor()?;
+        sender.send(notify(0, 50).as_bytes())?;
+
+        // when
+        let events = monitor.poll(Duration::from_millis(100))?;
+
+        // then
+        assert_eq!(events.len(), 2);
+        assert!(matches!(&events[0], PresenceEvent::Appeared(_)));
+        assert!(matches!(&events[1], PresenceEvent::Expired { id } if id == "0x000000000015243f"));
+        assert_eq!(monitor.ids().count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn expire_on_byebye() -> anyhow::Result<()> {
+        // given
+        let (mut monitor, sender) = local_monitor()?;
+        sender.send(notify(3600, 50).as_bytes())?;
+        sender.send(notify(3600, 50).replace("ssdp:alive", "ssdp:byebye").as_bytes())?;
+
+        // when
+        let events = monitor.poll(Duration::from_millis(100))?;
+
+        // then
+        assert_eq!(events.len(), 2);
+        assert!(matches!(&events[1], PresenceEvent::Expired { .. }));
+        Ok(())
+    }
+}
@@ -0,0 +1,241 @@
+use std::io::{BufWriter, ErrorKind, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use serde_json::json;
+
+use crate::err::{io_or_timeout, YeeError};
+use crate::fields::{PowerStatus, Rgb};
+use crate::flow::Flow;
+use crate::light::{Channel, Light};
+use crate::req::{AdjustAction, AdjustProp, Command, Req, Transition};
+use crate::scene::Scene;
+
+/// A light in music mode.
+///
+/// The light connects back to a server we host, and commands sent over that connection
+/// get no response and are not limited by the command quota.
+/// Since nothing is confirmed, the cached fields are updated as soon as a command is written.
+/// Music mode is turned off when the session is dropped.
+#[derive(Debug)]
+pub struct MusicSession<'a> {
+    light: &'a mut Light,
+    writer: BufWriter<TcpStream>,
+    stopped: bool,
+}
+
+impl<'a> MusicSession<'a> {
+    /// Starts music mode, waiting up to `timeout` for the light to connect back.
+    pub fn new(light: &'a mut Light, timeout: Duration) -> Result<MusicSession<'a>, YeeError> {
+        light.check_support("set_music")?;
+        // listen on the address the light already reaches us on
+        let host = light.connection()?.local_addr()?.ip();
+        let listener = TcpListener::bind((host, 0))?;
+        listener.set_nonblocking(true)?;
+        let port = listener.local_addr()?.port();
+
+        let req = Req::new("set_music".to_string(), vec![json!(1), json!(host.to_string()), json!(port)]);
+        light.send_req(req)?;
+
+        let start = Instant::now();
+        let stream = loop {
+            match listener.accept() {
+                Ok((stream, _)) => break stream,
+                Err(e) if e.kind() == ErrorKind::WouldBlock && start.elapsed() < timeout => {
+                    sleep(Duration::from_millis(10));
+                }
+                Err(e) => {
+                    let _ = light.send_req(stop_req());
+                    return Err(io_or_timeout("accept the music connection")(e));
+                }
+            }
+        };
+        stream.set_nonblocking(false)?;
+        stream.set_write_timeout(light.write_timeout())?;
+
+        Ok(MusicSession { light, writer: BufWriter::new(stream), stopped: false })
+    }
+
+    pub fn light(&self) -> &Light {
+        self.light
+    }
+
+    pub fn set_ct_abx(&mut self, temperature: u16, transition: Transition) -> Result<(), YeeError> {
+        let cmd = self.light.set_ct_abx_cmd(Channel::Main, temperature, transition)?;
+        self.send(cmd)
+    }
+
+    pub fn set_rgb(&mut self, rgb: Rgb, transition: Transition) -> Result<(), YeeError> {
+        let cmd = self.light.set_rgb_cmd(Channel::Main, rgb, transition)?;
+        self.send(cmd)
+    }
+
+    pub fn set_bright(&mut self, brightness: u8, transition: Transition) -> Result<(), YeeError> {
+        let cmd = self.light.set_bright_cmd(Channel::Main, brightness, transition)?;
+        self.send(cmd)
+    }
+
+    pub fn set_hsv(&mut self, hue: u16, sat: u8, transition: Transition) -> Result<(), YeeError> {
+        let cmd = self.light.set_hsv_cmd(Channel::Main, hue, sat, transition)?;
+        self.send(cmd)
+    }
+
+    pub fn set_power(&mut self, power: PowerStatus, transition: Transition) -> Result<(), YeeError> {
+        let cmd = self.light.set_power_cmd(Channel::Main, power, transition, None)?;
+        self.send(cmd)
+    }
+
+    pub fn toggle(&mut self) -> Result<(), YeeError> {
+        let cmd = self.light.toggle_cmd(Channel::Main)?;
+        self.send(cmd)
+    }
+
+    pub fn start_cf(&mut self, flow: &Flow) -> Result<(), YeeError> {
+        let cmd = self.light.start_cf_cmd(Channel::Main, flow)?;
+        self.send(cmd)
+    }
+
+    pub fn stop_cf(&mut self) -> Result<(), YeeError> {
+        let cmd = self.light.stop_cf_cmd(Channel::Main)?;
+        self.send(cmd)
+    }
+
+    pub fn set_scene(&mut self, scene: &Scene) -> Result<(), YeeError> {
+        let cmd = self.light.set_scene_cmd(Channel::Main, scene)?;
+        self.send(cmd)
+    }
+
+    /// Unlike `Light::adjust`, the cached fields are not updated since nothing can be read back.
+    pub fn adjust(&mut self, action: AdjustAction, prop: AdjustProp) -> Result<(), YeeError> {
+        let cmd = self.light.adjust_cmd(Channel::Main, action, prop)?;
+        self.send(cmd)
+    }
+
+    pub fn adjust_bright(&mut self, percentage: i8, duration: Duration) -> Result<(), YeeError> {
+        let cmd = self.light.adjust_by_cmd(Channel::Main, AdjustProp::Bright, percentage, duration)?;
+        self.send(cmd)
+    }
+
+    pub fn adjust_ct(&mut self, percentage: i8, duration: Duration) -> Result<(), YeeError> {
+        let cmd = self.light.adjust_by_cmd(Channel::Main, AdjustProp::Ct, percentage, duration)?;
+        self.send(cmd)
+    }
+
+    pub fn adjust_color(&mut self, percentage: i8, duration: Duration) -> Result<(), YeeError> {
+        let cmd = self.light.adjust_by_cmd(Channel::Main, AdjustProp::Color, percentage, duration)?;
+        self.send(cmd)
+    }
+
+    /// Turns music mode off, reporting any error unlike dropping the session.
+    pub fn stop(mut self) -> Result<(), YeeError> {
+        self.stopped = true;
+        self.light.send_req(stop_req())?;
+        Ok(())
+    }
+
+    fn send(&mut self, cmd: Command) -> Result<(), YeeError> {
+        let mut json = serde_json::to_string(&cmd.req).unwrap();
+        json.push_str("\r\n");
+        self.writer.write_all(json.as_bytes())
+            .and_then(|_| self.writer.flush())
+            .map_err(io_or_timeout("send a request"))?;
+        self.light.apply_all(cmd.changes);
+        Ok(())
+    }
+}
+
+impl Drop for MusicSession<'_> {
+    fn drop(&mut self) {
+        if !self.stopped {
+            let _ = self.light.send_req(stop_req());
+        }
+    }
+}
+
+fn stop_req() -> Req {
+    Req::new("set_music".to_string(), vec![json!(0)])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader};
+    use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+    use std::thread;
+
+    use serde_json::Value;
+
+    use crate::light::tests::{connected_light, light_at};
+
+    use super::*;
+
+    #[test]
+    fn sends_over_music_connection() -> anyhow::Result<()> {
+        // given
+        let listener = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))?;
+        let addr = listener.local_addr()?;
+        let handle = thread::spawn(move || {
+            let (control, _) = listener.accept().unwrap();
+            let mut control_reader = BufReader::new(control.try_clone().unwrap());
+            let mut control_writer = control;
+            let mut reply = |expected_params: Value| {
+                let mut line = String::new();
+                control_reader.read_line(&mut line).unwrap();
+                let req: Value = serde_json::from_str(&line).unwrap();
+                assert_eq!(req["method"], "set_music");
+                write!(control_writer, "{{\"id\":{},\"result\":[\"ok\"]}}\r\n", req["id"]).unwrap();
+                if expected_params.is_null() {
+                    req["params"].clone()
+                } else {
+                    assert_eq!(req["params"], expected_params);
+                    Value::Null
+                }
+            };
+
+            let params = reply(Value::Null);
+            let music_addr: SocketAddr = format!("{}:{}", params[1].as_str().unwrap(), params[2]).parse().unwrap();
+            let music = TcpStream::connect(music_addr).unwrap();
+            let mut music_reader = BufReader::new(music);
+            let mut received = Vec::new();
+            for _ in 0..2 {
+                let mut line = String::new();
+                music_reader.read_line(&mut line).unwrap();
+                received.push(serde_json::from_str::<Value>(&line).unwrap());
+            }
+            reply(json!([0]));
+            received
+        });
+        let mut light = light_at(addr, "set_music set_bright set_rgb")?;
+
+        // when
+        {
+            let mut session = MusicSession::new(&mut light, Duration::from_secs(2))?;
+            session.set_bright(10, Transition::sudden())?;
+            session.set_rgb(Rgb::new(1, 2, 3), Transition::sudden())?;
+        }
+
+        // then
+        let received = handle.join().unwrap();
+        assert_eq!(received[0]["method"], "set_bright");
+        assert_eq!(received[1]["params"], json!([66051, "sudden", 0]));
+        assert_eq!(light.bright(), 10);
+        assert_eq!(light.rgb(), &Rgb::new(1, 2, 3));
+        Ok(())
+    }
+
+    #[test]
+    fn times_out_without_connection() -> anyhow::Result<()> {
+        // given
+        let (mut light, handle) = connected_light(
+            "set_music", vec![r#""result":["ok"]"#, r#""result":["ok"]"#])?;
+
+        // when
+        let result = MusicSession::new(&mut light, Duration::from_millis(100));
+
+        // then
+        assert!(result.is_err());
+        let received = handle.join().unwrap();
+        assert_eq!(received[1]["params"], json!([0]));
+        Ok(())
+    }
+}
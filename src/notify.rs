@@ -0,0 +1,71 @@
+use std::collections::HashMap;
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::prop::{PropValue, Property};
+
+/// A change the light reported on its own, e.g. after being controlled from the app or a wall switch.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct PropertyChange {
+    pub property: Property,
+    pub value: PropValue,
+}
+
+#[derive(Deserialize, Debug)]
+struct Notification {
+    method: String,
+    params: HashMap<String, Value>,
+}
+
+/// Parses a `props` notification line, or returns `None` if the line is not one.
+///
+/// Properties this library doesn't know about or can't decode are skipped.
+pub fn parse_notification(line: &str) -> Option<Vec<PropertyChange>> {
+    let notification = serde_json::from_str::<Notification>(line).ok()
+        .filter(|n| n.method == "props")?;
+    let mut changes: Vec<PropertyChange> = notification.params.into_iter()
+        .filter_map(|(name, value)| {
+            let property = name.parse::<Property>().ok()?;
+            let raw = match value {
+                Value::String(s) => s,
+                other => other.to_string(),
+            };
+            let value = property.decode(&raw).ok()?;
+            Some(PropertyChange { property, value })
+        })
+        .collect();
+    // params come from a map, so keep the order stable for callers
+    changes.sort_by_key(|c| c.property.name());
+    Some(changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fields::PowerStatus;
+
+    use super::*;
+
+    #[test]
+    fn parse_props() {
+        // given
+        let line = r#"{"method":"props","params":{"power":"on","bright":10,"main_power":"on","ct":"x"}}"#;
+
+        // when
+        let changes = parse_notification(line);
+
+        // then
+        assert_eq!(changes, Some(vec![
+            PropertyChange { property: Property::Bright, value: PropValue::Bright(10) },
+            PropertyChange { property: Property::Power, value: PropValue::Power(PowerStatus::On) },
+        ]));
+    }
+
+    #[test]
+    fn ignore_other_lines() {
+        // then
+        assert_eq!(parse_notification(r#"{"id":1,"result":["ok"]}"#), None);
+        assert_eq!(parse_notification(r#"{"method":"other","params":{"power":"on"}}"#), None);
+        assert_eq!(parse_notification("not json"), None);
+    }
+}
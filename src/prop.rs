@@ -0,0 +1,279 @@
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::err::YeeError;
+use crate::fields::{ActiveMode, ColorMode, PowerStatus, Rgb};
+
+/// A property that can be read from a light with `get_prop`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum Property {
+    Power,
+    Bright,
+    Ct,
+    Rgb,
+    Hue,
+    Sat,
+    ColorMode,
+    Flowing,
+    DelayOff,
+    MusicOn,
+    Name,
+    BgPower,
+    BgFlowing,
+    BgCt,
+    BgColorMode,
+    BgBright,
+    BgRgb,
+    BgHue,
+    BgSat,
+    NlBr,
+    ActiveMode,
+}
+
+/// A decoded property value, using the same types as the cached fields on a `Light`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum PropValue {
+    Power(PowerStatus),
+    Bright(u8),
+    Ct(u16),
+    Rgb(Rgb),
+    Hue(u16),
+    Sat(u8),
+    ColorMode(ColorMode),
+    ActiveMode(ActiveMode),
+    Flag(bool),
+    // rounded to whole minutes by the light
+    Minutes(Duration),
+    Name(String),
+}
+
+impl Property {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Power => "power",
+            Self::Bright => "bright",
+            Self::Ct => "ct",
+            Self::Rgb => "rgb",
+            Self::Hue => "hue",
+            Self::Sat => "sat",
+            Self::ColorMode => "color_mode",
+            Self::Flowing => "flowing",
+            Self::DelayOff => "delayoff",
+            Self::MusicOn => "music_on",
+            Self::Name => "name",
+            Self::BgPower => "bg_power",
+            Self::BgFlowing => "bg_flowing",
+            Self::BgCt => "bg_ct",
+            Self::BgColorMode => "bg_lmode",
+            Self::BgBright => "bg_bright",
+            Self::BgRgb => "bg_rgb",
+            Self::BgHue => "bg_hue",
+            Self::BgSat => "bg_sat",
+            Self::NlBr => "nl_br",
+            Self::ActiveMode => "active_mode",
+        }
+    }
+
+    /// The same property on the background light of dual-light models, if there is one.
+    pub fn background(&self) -> Option<Property> {
+        match self {
+            Self::Power => Some(Self::BgPower),
+            Self::Bright => Some(Self::BgBright),
+            Self::Ct => Some(Self::BgCt),
+            Self::Rgb => Some(Self::BgRgb),
+            Self::Hue => Some(Self::BgHue),
+            Self::Sat => Some(Self::BgSat),
+            Self::ColorMode => Some(Self::BgColorMode),
+            Self::Flowing => Some(Self::BgFlowing),
+            _ => None
+        }
+    }
+
+    /// Decodes the raw string the light sent for this property.
+    pub fn decode(&self, raw: &str) -> Result<PropValue, YeeError> {
+        let field_name = self.name();
+        let parse_err = |e| YeeError::ParseFieldFailed { field_name, source: Some(e) };
+        Ok(match self {
+            Self::Power | Self::BgPower => PropValue::Power(raw.parse()?),
+            Self::Bright | Self::BgBright | Self::NlBr => PropValue::Bright(raw.parse().map_err(parse_err)?),
+            Self::Ct | Self::BgCt => PropValue::Ct(raw.parse().map_err(parse_err)?),
+            Self::Rgb | Self::BgRgb => PropValue::Rgb(raw.parse()?),
+            Self::Hue | Self::BgHue => PropValue::Hue(raw.parse().map_err(parse_err)?),
+            Self::Sat | Self::BgSat => PropValue::Sat(raw.parse().map_err(parse_err)?),
+            Self::ColorMode | Self::BgColorMode => PropValue::ColorMode(raw.parse()?),
+            Self::ActiveMode => PropValue::ActiveMode(raw.parse()?),
+            Self::Flowing | Self::BgFlowing | Self::MusicOn => match raw {
+                "0" => PropValue::Flag(false),
+                "1" => PropValue::Flag(true),
+                _ => return Err(YeeError::ParseFieldFailed { field_name, source: None })
+            },
+            Self::DelayOff => {
+                let minutes: u64 = raw.parse().map_err(parse_err)?;
+                let secs = minutes.checked_mul(60).ok_or(YeeError::ParseFieldFailed { field_name, source: None })?;
+                PropValue::Minutes(Duration::from_secs(secs))
+            }
+            Self::Name => PropValue::Name(decode_name(raw)),
+        })
+    }
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// The name as it is stored on the light, base64 if it isn't ASCII, as the spec suggests.
+pub(crate) fn encode_name(name: &str) -> String {
+    if name.is_ascii() {
+        return name.to_string();
+    }
+    let mut encoded = String::with_capacity(name.len().div_ceil(3) * 4);
+    for chunk in name.as_bytes().chunks(3) {
+        let bytes = [chunk[0], chunk.get(1).copied().unwrap_or(0), chunk.get(2).copied().unwrap_or(0)];
+        let n = u32::from(bytes[0]) << 16 | u32::from(bytes[1]) << 8 | u32::from(bytes[2]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(BASE64[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+/// Reverses `encode_name`, a name is only taken as base64 if it decodes to text that isn't ASCII.
+pub(crate) fn decode_name(raw: &str) -> String {
+    decode_base64(raw)
+        .and_then(|bytes| String::from_utf8(bytes).ok())
+        .filter(|name| !name.is_ascii())
+        .unwrap_or_else(|| raw.to_string())
+}
+
+fn decode_base64(raw: &str) -> Option<Vec<u8>> {
+    let raw = raw.as_bytes();
+    if raw.is_empty() || !raw.len().is_multiple_of(4) {
+        return None;
+    }
+    let chunks = raw.len() / 4;
+    let mut decoded = Vec::with_capacity(chunks * 3);
+    for (index, chunk) in raw.chunks(4).enumerate() {
+        let padding = chunk.iter().rev().take_while(|&&c| c == b'=').count();
+        // only the last chunk is padded
+        if padding > 2 || (padding > 0 && index + 1 < chunks) {
+            return None;
+        }
+        let mut n = 0u32;
+        for c in &chunk[..4 - padding] {
+            n = n << 6 | BASE64.iter().position(|b| b == c)? as u32;
+        }
+        n <<= 6 * padding as u32;
+        decoded.extend_from_slice(&n.to_be_bytes()[1..4 - padding]);
+    }
+    Some(decoded)
+}
+
+impl Display for Property {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for Property {
+    type Err = YeeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "power" => Self::Power,
+            "bright" => Self::Bright,
+            "ct" => Self::Ct,
+            "rgb" => Self::Rgb,
+            "hue" => Self::Hue,
+            "sat" => Self::Sat,
+            "color_mode" => Self::ColorMode,
+            "flowing" => Self::Flowing,
+            "delayoff" => Self::DelayOff,
+            "music_on" => Self::MusicOn,
+            "name" => Self::Name,
+            "bg_power" => Self::BgPower,
+            "bg_flowing" => Self::BgFlowing,
+            "bg_ct" => Self::BgCt,
+            "bg_lmode" => Self::BgColorMode,
+            "bg_bright" => Self::BgBright,
+            "bg_rgb" => Self::BgRgb,
+            "bg_hue" => Self::BgHue,
+            "bg_sat" => Self::BgSat,
+            "nl_br" => Self::NlBr,
+            "active_mode" => Self::ActiveMode,
+            _ => return Err(YeeError::InvalidValue { field_name: "property", value: s.to_string() })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn name_round_trips() -> anyhow::Result<()> {
+        // given
+        let props = [Property::Power, Property::ColorMode, Property::DelayOff, Property::BgColorMode, Property::NlBr,
+                     Property::ActiveMode];
+
+        // when
+        let parsed = props.iter()
+            .map(|p| p.name().parse::<Property>())
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // then
+        assert_eq!(parsed, props.to_vec());
+        Ok(())
+    }
+
+    #[test]
+    fn correct_decode() -> anyhow::Result<()> {
+        // when
+        let power = Property::BgPower.decode("off")?;
+        let bright = Property::NlBr.decode("12")?;
+        let rgb = Property::Rgb.decode("657930")?;
+        let mode = Property::ColorMode.decode("3")?;
+        let flowing = Property::Flowing.decode("1")?;
+        let delay = Property::DelayOff.decode("15")?;
+        let active = Property::ActiveMode.decode("1")?;
+
+        // then
+        assert_eq!(power, PropValue::Power(PowerStatus::Off));
+        assert_eq!(bright, PropValue::Bright(12));
+        assert_eq!(rgb, PropValue::Rgb(Rgb::new(10, 10, 10)));
+        assert_eq!(mode, PropValue::ColorMode(ColorMode::Hsv));
+        assert_eq!(flowing, PropValue::Flag(true));
+        assert_eq!(delay, PropValue::Minutes(Duration::from_secs(900)));
+        assert_eq!(active, PropValue::ActiveMode(ActiveMode::Moonlight));
+        Ok(())
+    }
+
+    #[test]
+    fn name_round_trips_as_base64() {
+        // when
+        let encoded = encode_name("salón");
+
+        // then
+        assert_eq!(encoded, "c2Fsw7Nu");
+        assert_eq!(decode_name(&encoded), "salón");
+        assert_eq!(encode_name("bedroom"), "bedroom");
+        // ASCII names are kept, even if they happen to be base64
+        assert_eq!(decode_name("bedroom"), "bedroom");
+        assert_eq!(decode_name("abcd"), "abcd");
+        assert_eq!(encode_name("日本"), "5pel5pys");
+        assert_eq!(decode_name("5pel5pys"), "日本");
+        assert_eq!(decode_name("w6k="), "é");
+    }
+
+    #[test]
+    fn incorrect_decode() {
+        // then
+        assert!(Property::Bright.decode("bright").is_err());
+        assert!(Property::MusicOn.decode("2").is_err());
+        assert!(Property::ColorMode.decode("").is_err());
+        assert!(Property::DelayOff.decode(&u64::MAX.to_string()).is_err());
+        assert!("not_a_prop".parse::<Property>().is_err());
+    }
+}
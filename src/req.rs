@@ -3,8 +3,11 @@ use std::time::Duration;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
+use crate::err::YeeError;
+use crate::prop::{PropValue, Property};
+
 /// this is a req
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Req {
     pub id: u16,
     pub method: String,
@@ -12,12 +15,60 @@ pub struct Req {
 }
 
 impl Req {
+    #[deprecated(note = "the connection replaces the id when the request is sent, use `Req::new`")]
     pub fn with_id(id: u16, method: String, params: Vec<Value>) -> Req {
         Req { id, method, params }
     }
+    /// The id is assigned by the connection when the request is sent.
     pub fn new(method: String, params: Vec<Value>) -> Req {
-        let id = fastrand::u16(..);
-        Req { id, method, params }
+        Req { id: 0, method, params }
+    }
+}
+
+/// A response from the light, either `{id, result}` or `{id, error: {code, message}}`.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct Response {
+    pub id: u16,
+    #[serde(default)]
+    pub result: Option<Vec<Value>>,
+    #[serde(default)]
+    pub error: Option<ResponseError>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct ResponseError {
+    pub code: i32,
+    pub message: String,
+}
+
+impl Response {
+    pub fn into_result(self) -> Result<Vec<Value>, YeeError> {
+        match (self.result, self.error) {
+            (_, Some(ResponseError { code, message })) => Err(YeeError::Device { code, message }),
+            (Some(result), None) => Ok(result),
+            (None, None) => Err(YeeError::FieldNotFound { field_name: "result" })
+        }
+    }
+}
+
+/// A request along with the changes to the cached fields once the light has accepted it.
+#[derive(Debug)]
+pub(crate) struct Command {
+    pub(crate) req: Req,
+    pub(crate) changes: Vec<(Property, PropValue)>,
+    // safe to send again if the connection broke before the response arrived
+    pub(crate) idempotent: bool,
+}
+
+impl Command {
+    pub(crate) fn new(req: Req, changes: Vec<(Property, PropValue)>) -> Command {
+        Command { req, changes, idempotent: false }
+    }
+
+    /// Marks the command as safe to retry, i.e. sending it twice has the same effect as sending it once.
+    pub(crate) fn idempotent(mut self) -> Command {
+        self.idempotent = true;
+        self
     }
 }
 
@@ -58,11 +109,99 @@ impl Transition {
     }
 }
 
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum AdjustAction {
+    Increase,
+    Decrease,
+    /// increase, going back to the minimum after the maximum is reached
+    Circle,
+}
+
+impl AdjustAction {
+    pub fn text(&self) -> &'static str {
+        match self {
+            Self::Increase => "increase",
+            Self::Decrease => "decrease",
+            Self::Circle => "circle"
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum AdjustProp {
+    Bright,
+    Ct,
+    // can only be used with AdjustAction::Circle
+    Color,
+}
+
+impl AdjustProp {
+    pub fn text(&self) -> &'static str {
+        match self {
+            Self::Bright => "bright",
+            Self::Ct => "ct",
+            Self::Color => "color"
+        }
+    }
+}
+
+/// Rounds a delay to the whole minutes the light works with, which must be at least one.
+pub(crate) fn whole_minutes(delay: Duration) -> Result<u64, YeeError> {
+    let minutes = delay.as_secs().saturating_add(30) / 60;
+    if minutes == 0 {
+        Err(YeeError::InvalidValue { field_name: "minutes", value: format!("{:?}", delay) })
+    } else {
+        Ok(minutes)
+    }
+}
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[test]
-    fn test() {}
+    fn smooth_transition_bounds() {
+        // when
+        let too_short = Transition::smooth(Duration::from_millis(29));
+        let shortest = Transition::smooth(Duration::from_millis(30));
+
+        // then
+        assert!(too_short.is_none());
+        assert_eq!(shortest.map(|t| t.value()), Some(30));
+        assert_eq!(Transition::sudden().text(), "sudden");
+    }
+
+    #[test]
+    fn decode_response() -> anyhow::Result<()> {
+        // given
+        let ok = r#"{"id":12,"result":["ok"]}"#;
+        let error = r#"{"id":3,"error":{"code":-1,"message":"unsupported method"}}"#;
+        let empty = r#"{"id":4}"#;
+
+        // when
+        let ok = serde_json::from_str::<Response>(ok)?;
+        let error = serde_json::from_str::<Response>(error)?;
+        let empty = serde_json::from_str::<Response>(empty)?;
+
+        // then
+        assert_eq!(ok.id, 12);
+        assert_eq!(ok.into_result()?, vec![Value::from("ok")]);
+        assert_eq!(error.id, 3);
+        assert!(matches!(error.into_result(),
+                         Err(YeeError::Device { code: -1, ref message }) if message == "unsupported method"));
+        assert!(empty.into_result().is_err());
+        assert!(serde_json::from_str::<Response>(r#"{"method":"props","params":{"power":"on"}}"#).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn rounds_to_whole_minutes() -> anyhow::Result<()> {
+        // then
+        assert_eq!(whole_minutes(Duration::from_secs(90))?, 2);
+        assert_eq!(whole_minutes(Duration::from_secs(89))?, 1);
+        assert_eq!(whole_minutes(Duration::from_secs(3600))?, 60);
+        assert!(whole_minutes(Duration::from_secs(29)).is_err());
+        assert_eq!(whole_minutes(Duration::from_secs(u64::MAX))?, u64::MAX / 60);
+        Ok(())
+    }
 }
\ No newline at end of file
@@ -0,0 +1,77 @@
+use std::time::Duration;
+
+/// How a `Light` reconnects after its connection broke, e.g. when the light closed an idle connection or rebooted.
+///
+/// Reconnecting is attempted before the next call on the light, and a call retries at most `max_retries` times,
+/// whether connecting failed or the command was sent again.
+/// Only commands that can safely be sent twice, like the absolute setters, are sent again after the connection broke
+/// or the response timed out; relative ones like `toggle` and `adjust` return the error instead.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct RetryPolicy {
+    max_retries: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// Waits `initial_backoff` before the first retry, doubling up to `max_backoff` for each next one.
+    pub fn new(max_retries: u32, initial_backoff: Duration, max_backoff: Duration) -> RetryPolicy {
+        RetryPolicy { max_retries, initial_backoff, max_backoff: max_backoff.max(initial_backoff) }
+    }
+
+    /// Never reconnects or retries, every call fails once the connection broke.
+    pub fn never() -> RetryPolicy {
+        RetryPolicy::new(0, Duration::from_secs(0), Duration::from_secs(0))
+    }
+
+    pub fn max_retries(&self) -> u32 {
+        self.max_retries
+    }
+
+    pub fn initial_backoff(&self) -> Duration {
+        self.initial_backoff
+    }
+
+    pub fn max_backoff(&self) -> Duration {
+        self.max_backoff
+    }
+
+    /// The time to wait before the given retry, starting at 1.
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let factor = 2u32.saturating_pow(retry.saturating_sub(1));
+        self.initial_backoff.checked_mul(factor)
+            .map_or(self.max_backoff, |backoff| backoff.min(self.max_backoff))
+    }
+}
+
+impl Default for RetryPolicy {
+    /// 3 retries, waiting from 100ms up to 2s.
+    fn default() -> Self {
+        RetryPolicy::new(3, Duration::from_millis(100), Duration::from_secs(2))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_max() {
+        // given
+        let policy = RetryPolicy::new(10, Duration::from_millis(100), Duration::from_millis(500));
+
+        // then
+        assert_eq!(policy.backoff(1), Duration::from_millis(100));
+        assert_eq!(policy.backoff(2), Duration::from_millis(200));
+        assert_eq!(policy.backoff(3), Duration::from_millis(400));
+        assert_eq!(policy.backoff(4), Duration::from_millis(500));
+        assert_eq!(policy.backoff(40), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn never_retries() {
+        // then
+        assert_eq!(RetryPolicy::never().max_retries(), 0);
+        assert_eq!(RetryPolicy::default().max_retries(), 3);
+    }
+}
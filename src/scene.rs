@@ -0,0 +1,102 @@
+use std::time::Duration;
+
+use serde_json::{json, Value};
+
+use crate::err::YeeError;
+use crate::fields::Rgb;
+use crate::flow::Flow;
+use crate::req::whole_minutes;
+
+/// A state the light can be turned on into directly with `set_scene`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Scene {
+    Color { rgb: Rgb, bright: u8 },
+    Hsv { hue: u16, sat: u8, bright: u8 },
+    Ct { ct: u16, bright: u8 },
+    Cf(Flow),
+    /// turn on at the given brightness, then turn off after the delay
+    AutoDelayOff { bright: u8, delay: Duration },
+}
+
+impl Scene {
+    pub fn class(&self) -> &'static str {
+        match self {
+            Self::Color { .. } => "color",
+            Self::Hsv { .. } => "hsv",
+            Self::Ct { .. } => "ct",
+            Self::Cf(_) => "cf",
+            Self::AutoDelayOff { .. } => "auto_delay_off"
+        }
+    }
+
+    /// Validates the scene and returns the `set_scene` params, including the class.
+    pub fn params(&self) -> Result<Vec<Value>, YeeError> {
+        let class = json!(self.class());
+        Ok(match self {
+            Self::Color { rgb, bright } => vec![class, json!(rgb.get_num()), json!(check_bright(*bright)?)],
+            Self::Hsv { hue, sat, bright } => {
+                if !(0..=359).contains(hue) {
+                    return Err(YeeError::InvalidValue { field_name: "hue", value: hue.to_string() });
+                } else if !(0..=100).contains(sat) {
+                    return Err(YeeError::InvalidValue { field_name: "sat", value: sat.to_string() });
+                }
+                vec![class, json!(hue), json!(sat), json!(check_bright(*bright)?)]
+            }
+            Self::Ct { ct, bright } => {
+                // same bounds as set_ct_abx
+                if !(2700..=6500).contains(ct) {
+                    return Err(YeeError::InvalidValue { field_name: "ct", value: ct.to_string() });
+                }
+                vec![class, json!(ct), json!(check_bright(*bright)?)]
+            }
+            Self::Cf(flow) => vec![class, json!(flow.count()), json!(flow.action().value()), json!(flow.expression())],
+            Self::AutoDelayOff { bright, delay } => {
+                let minutes = whole_minutes(*delay)?;
+                vec![class, json!(check_bright(*bright)?), json!(minutes)]
+            }
+        })
+    }
+}
+
+fn check_bright(bright: u8) -> Result<u8, YeeError> {
+    if (1..=100).contains(&bright) {
+        Ok(bright)
+    } else {
+        Err(YeeError::InvalidValue { field_name: "bright", value: bright.to_string() })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::flow::FlowAction;
+
+    use super::*;
+
+    #[test]
+    fn correct_params() -> anyhow::Result<()> {
+        // given
+        let color = Scene::Color { rgb: Rgb::new(255, 0, 0), bright: 70 };
+        let hsv = Scene::Hsv { hue: 300, sat: 70, bright: 100 };
+        let ct = Scene::Ct { ct: 5400, bright: 100 };
+        let cf = Scene::Cf(Flow::parse(0, FlowAction::Stay, "500,1,255,100,1000,1,16776960,70")?);
+        let delay = Scene::AutoDelayOff { bright: 50, delay: Duration::from_secs(5 * 60) };
+
+        // then
+        assert_eq!(json!(color.params()?), json!(["color", 16711680, 70]));
+        assert_eq!(json!(hsv.params()?), json!(["hsv", 300, 70, 100]));
+        assert_eq!(json!(ct.params()?), json!(["ct", 5400, 100]));
+        assert_eq!(json!(cf.params()?), json!(["cf", 0, 1, "500,1,255,100,1000,1,16776960,70"]));
+        assert_eq!(json!(delay.params()?), json!(["auto_delay_off", 50, 5]));
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_params() {
+        // then
+        assert!(Scene::Color { rgb: Rgb::empty(), bright: 0 }.params().is_err());
+        assert!(Scene::Hsv { hue: 360, sat: 10, bright: 10 }.params().is_err());
+        assert!(Scene::Hsv { hue: 10, sat: 101, bright: 10 }.params().is_err());
+        assert!(Scene::Ct { ct: 1000, bright: 10 }.params().is_err());
+        assert!(Scene::AutoDelayOff { bright: 10, delay: Duration::from_secs(10) }.params().is_err());
+    }
+}